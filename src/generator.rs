@@ -0,0 +1,214 @@
+//! Deterministic streams of valid transactions for tests and benchmarks.
+//!
+//! Unlike `test_data/generate.py`, the generator keeps a mirror of every client's balance so
+//! withdrawals stay within available funds, disputes point at real deposits of the same client
+//! and resolves/chargebacks only follow open disputes. Locked clients receive no further
+//! transactions.
+
+use crate::{Transaction, TransactionState, TransactionType};
+
+/// Relative weights of each transaction type in a generated stream.
+pub(crate) struct Mix {
+    pub(crate) deposit: u32,
+    pub(crate) withdrawal: u32,
+    pub(crate) dispute: u32,
+    pub(crate) resolve: u32,
+    pub(crate) chargeback: u32,
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Self {
+            deposit: 60,
+            withdrawal: 25,
+            dispute: 8,
+            resolve: 5,
+            chargeback: 2,
+        }
+    }
+}
+
+impl Mix {
+    fn total(&self) -> u32 {
+        self.deposit + self.withdrawal + self.dispute + self.resolve + self.chargeback
+    }
+}
+
+/// SplitMix64, small and good enough to drive test data.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+#[derive(Default, Clone)]
+struct Balance {
+    /// Available funds in cents.
+    available: u64,
+    locked: bool,
+}
+
+pub(crate) struct Generator {
+    rng: Rng,
+    pub(crate) mix: Mix,
+    balances: Vec<Balance>,
+    next_tx: u32,
+    /// Deposits that were never disputed: (tx, client, cents).
+    deposits: Vec<(u32, u16, u64)>,
+    /// Deposits with an open dispute: (tx, client, cents).
+    disputed: Vec<(u32, u16, u64)>,
+}
+
+impl Generator {
+    pub(crate) fn new(seed: u64, clients: u16) -> Self {
+        Self {
+            rng: Rng(seed),
+            mix: Mix::default(),
+            balances: vec![Balance::default(); clients as usize],
+            next_tx: 1,
+            deposits: Vec::new(),
+            disputed: Vec::new(),
+        }
+    }
+
+    fn transaction(kind: TransactionType, client: u16, tx: u32, cents: u64) -> Transaction {
+        Transaction {
+            kind,
+            client,
+            tx,
+            amount: cents as f64 / 100.0,
+            state: TransactionState::None,
+            held: 0.0,
+        }
+    }
+
+    fn balance(&mut self, client: u16) -> &mut Balance {
+        &mut self.balances[client as usize - 1]
+    }
+
+    fn random_client(&mut self) -> Option<u16> {
+        let unlocked = self.balances.iter().filter(|b| !b.locked).count() as u64;
+        if unlocked == 0 {
+            return None;
+        }
+
+        let pick = self.rng.below(unlocked) as usize;
+        let (index, _) = self
+            .balances
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| !b.locked)
+            .nth(pick)?;
+        Some(index as u16 + 1)
+    }
+
+    fn deposit(&mut self, client: u16) -> Transaction {
+        let cents = 1 + self.rng.below(100_000);
+        let tx = self.next_tx;
+        self.next_tx += 1;
+
+        self.balance(client).available += cents;
+        self.deposits.push((tx, client, cents));
+        Self::transaction(TransactionType::Deposit, client, tx, cents)
+    }
+
+    fn withdrawal(&mut self, client: u16) -> Option<Transaction> {
+        let available = self.balance(client).available;
+        if available == 0 {
+            return None;
+        }
+
+        let cents = 1 + self.rng.below(available);
+        let tx = self.next_tx;
+        self.next_tx += 1;
+
+        self.balance(client).available -= cents;
+        Some(Self::transaction(
+            TransactionType::Withdrawal,
+            client,
+            tx,
+            cents,
+        ))
+    }
+
+    fn dispute(&mut self) -> Option<Transaction> {
+        // only disputes the client can cover, so available never goes negative
+        let candidates: Vec<usize> = (0..self.deposits.len())
+            .filter(|&i| {
+                let (_, client, cents) = self.deposits[i];
+                let balance = &self.balances[client as usize - 1];
+                !balance.locked && balance.available >= cents
+            })
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = candidates[self.rng.below(candidates.len() as u64) as usize];
+        let (tx, client, cents) = self.deposits.swap_remove(index);
+        self.balance(client).available -= cents;
+        self.disputed.push((tx, client, cents));
+        Some(Self::transaction(TransactionType::Dispute, client, tx, 0))
+    }
+
+    fn settle(&mut self, chargeback: bool) -> Option<Transaction> {
+        if self.disputed.is_empty() {
+            return None;
+        }
+
+        let index = self.rng.below(self.disputed.len() as u64) as usize;
+        let (tx, client, cents) = self.disputed.swap_remove(index);
+        if chargeback {
+            self.balance(client).locked = true;
+            self.deposits.retain(|&(_, c, _)| c != client);
+            self.disputed.retain(|&(_, c, _)| c != client);
+            Some(Self::transaction(
+                TransactionType::Chargeback,
+                client,
+                tx,
+                0,
+            ))
+        } else {
+            self.balance(client).available += cents;
+            Some(Self::transaction(TransactionType::Resolve, client, tx, 0))
+        }
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        let client = self.random_client()?;
+
+        let roll = self.rng.below(self.mix.total() as u64) as u32;
+        let withdrawal = self.mix.deposit + self.mix.withdrawal;
+        let dispute = withdrawal + self.mix.dispute;
+        let resolve = dispute + self.mix.resolve;
+
+        let transaction = if roll < self.mix.deposit {
+            None
+        } else if roll < withdrawal {
+            self.withdrawal(client)
+        } else if roll < dispute {
+            self.dispute()
+        } else if roll < resolve {
+            self.settle(false)
+        } else {
+            self.settle(true)
+        };
+
+        // fall back to a deposit whenever the chosen kind has nothing to act on
+        Some(transaction.unwrap_or_else(|| self.deposit(client)))
+    }
+}
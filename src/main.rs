@@ -1,15 +1,25 @@
-use csv::Reader;
-use serde::{Deserialize, Serialize};
+use arrow_array::{ArrayRef, BooleanArray, Decimal128Array, RecordBatch, UInt16Array, UInt32Array};
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 use std::cmp::PartialEq;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
+use std::fmt;
 use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::panic::resume_unwind;
 use std::process::exit;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
 use std::thread;
 use std::time::Instant;
 
-#[derive(Debug, Deserialize)]
+#[cfg(test)]
+mod generator;
+
+#[derive(Debug, Deserialize, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 enum TransactionType {
     Deposit,
@@ -19,66 +29,666 @@ enum TransactionType {
     Chargeback,
 }
 
-#[derive(PartialEq, Debug)]
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(PartialEq, Debug, Default)]
 enum TransactionState {
+    #[default]
     None,
     Dispute,
     Resolve,
     Chargeback,
 }
-impl Default for TransactionState {
-    fn default() -> Self {
-        TransactionState::None
+
+#[derive(Debug)]
+struct Transaction {
+    kind: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: f64,
+
+    state: TransactionState,
+    /// What is still held for this transaction while it is disputed.
+    held: f64,
+}
+
+/// Why the engine did not apply a transaction.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Rejection {
+    Locked,
+    NegativeAmount,
+    InsufficientFunds,
+    UnknownTransaction,
+    ForeignTransaction,
+    InvalidState,
+    AlreadyDisputed,
+    ExceedsHeld,
+    NegativeHeld,
+    NothingToHold,
+    /// Held back until the referenced transaction is disputed.
+    Pending,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            Rejection::Locked => "account is locked",
+            Rejection::NegativeAmount => "amount is negative",
+            Rejection::InsufficientFunds => "insufficient available funds",
+            Rejection::UnknownTransaction => "referenced transaction was never applied",
+            Rejection::ForeignTransaction => "referenced transaction belongs to another client",
+            Rejection::InvalidState => "referenced transaction is not in a state allowing this",
+            Rejection::AlreadyDisputed => "referenced transaction is already disputed",
+            Rejection::ExceedsHeld => "amount exceeds what is held for the transaction",
+            Rejection::NegativeHeld => "would drive held funds negative",
+            Rejection::NothingToHold => "referenced transaction has a zero amount",
+            Rejection::Pending => "referenced transaction is not disputed yet, held until it is",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// A row of the input carrying the transaction id under `--explain`, and what became of it.
+#[derive(Debug)]
+struct Event {
+    kind: TransactionType,
+    client: u16,
+    amount: f64,
+    result: Result<(), Rejection>,
+}
+
+#[derive(Debug, PartialEq)]
+enum AmountError {
+    Missing,
+    Invalid(String),
+}
+
+/// Reads `amount` without failing the whole row, so a bad amount can be told apart from
+/// other problems with the row. `NaN` and infinities parse as `f64` but are not amounts.
+fn deserialize_amount<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Result<f64, AmountError>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    if value.is_empty() {
+        return Ok(Err(AmountError::Missing));
+    }
+    match value.parse::<f64>() {
+        Ok(amount) if amount.is_finite() => Ok(Ok(amount)),
+        _ => Ok(Err(AmountError::Invalid(value))),
     }
 }
 
+/// A row of the input, before its amount has been checked.
 #[derive(Debug, Deserialize)]
-struct Transaction {
+struct InputRecord {
     #[serde(rename = "type")]
     kind: TransactionType,
     client: u16,
     tx: u32,
-    amount: f64,
+    #[serde(deserialize_with = "deserialize_amount")]
+    amount: Result<f64, AmountError>,
+}
 
-    #[serde(skip)]
-    state: TransactionState,
+/// Why a row of the input was dropped.
+#[derive(Debug, PartialEq)]
+enum RowError {
+    /// `amount` is present but is not a number.
+    InvalidAmount(String),
+    /// A field the engine needs is empty or absent.
+    MissingField(String),
+    /// Anything else, such as an unknown transaction type.
+    Malformed(String),
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RowError::InvalidAmount(value) => write!(f, "invalid amount '{}'", value),
+            RowError::MissingField(field) => write!(f, "missing field {}", field),
+            RowError::Malformed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl RowError {
+    /// Classifies an error reading `record`. A row ending early misses the field named by
+    /// the first header past its last field.
+    fn from_csv(err: &csv::Error, record: &csv::StringRecord, headers: &csv::StringRecord) -> Self {
+        if let csv::ErrorKind::Deserialize { err, .. } = err.kind() {
+            if let csv::DeserializeErrorKind::UnexpectedEndOfRow = err.kind() {
+                let field = headers.get(record.len()).unwrap_or_default();
+                return RowError::MissingField(field.to_string());
+            }
+        }
+        RowError::Malformed(err.to_string())
+    }
+}
+
+fn parse_record(
+    record: &csv::StringRecord,
+    headers: &csv::StringRecord,
+) -> Result<Transaction, RowError> {
+    // a row leaving out only a trailing amount reads as one with an empty amount
+    let mut padded;
+    let short = record.len() + 1 == headers.len() && headers.get(record.len()) == Some("amount");
+    let record = if short {
+        padded = record.clone();
+        padded.push_field("");
+        &padded
+    } else {
+        record
+    };
+
+    let input: InputRecord = record
+        .deserialize(Some(headers))
+        .map_err(|err| RowError::from_csv(&err, record, headers))?;
+    let amount = input.amount.or_else(|err| match (err, input.kind) {
+        // only deposits and withdrawals need an amount, a resolve without one releases everything
+        (
+            AmountError::Missing,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback,
+        ) => Ok(0.0),
+        (AmountError::Missing, _) => Err(RowError::MissingField("amount".to_string())),
+        (AmountError::Invalid(value), _) => Err(RowError::InvalidAmount(value)),
+    })?;
+
+    Ok(Transaction {
+        kind: input.kind,
+        client: input.client,
+        tx: input.tx,
+        amount,
+        state: TransactionState::None,
+        held: 0.0,
+    })
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug)]
 struct Client {
     client: u16,
     available: f64,
     held: f64,
     total: f64,
     locked: bool,
+    /// Disputes ever opened against this client, including resolved and charged back ones.
+    lifetime_disputes: u32,
+    /// Changed by a transaction past the `since_tx` mark.
+    changed: bool,
+    /// Had at least one transaction applied, rather than only rejected ones.
+    applied: bool,
 }
 
-struct PaymentEngine {
-    clients: HashMap<u16, Client>,
-    executed_transactions: HashMap<u32, Transaction>,
+/// How the `locked` column is written, for consumers that do not understand `true`/`false`.
+#[derive(Debug, Default, Clone, Copy)]
+enum LockedFormat {
+    #[default]
+    TrueFalse,
+    OneZero,
+    YesNo,
+}
+
+impl LockedFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "true-false" => Some(LockedFormat::TrueFalse),
+            "1-0" => Some(LockedFormat::OneZero),
+            "yes-no" => Some(LockedFormat::YesNo),
+            _ => None,
+        }
+    }
+}
+
+/// How client balances are written.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    #[default]
+    Csv,
+    /// A single Parquet row group with decimal amounts and a boolean `locked` column.
+    Parquet,
+}
+
+impl OutputFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "csv" => Some(OutputFormat::Csv),
+            "parquet" => Some(OutputFormat::Parquet),
+            _ => None,
+        }
+    }
+}
+
+/// Precision and scale of the decimal amount columns in Parquet output. Amounts carry up to
+/// four decimal places.
+const PARQUET_PRECISION: u8 = 20;
+const PARQUET_SCALE: i8 = 4;
+
+struct Locked(bool, LockedFormat);
+
+impl Serialize for Locked {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.1 {
+            LockedFormat::TrueFalse => serializer.serialize_bool(self.0),
+            LockedFormat::OneZero => serializer.serialize_u8(self.0 as u8),
+            LockedFormat::YesNo => serializer.serialize_str(if self.0 { "yes" } else { "no" }),
+        }
+    }
+}
+
+/// Writes `-0.0`, which chargebacks can leave behind, as `0.0`.
+fn serialize_amount<S: Serializer>(amount: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(if *amount == 0.0 { 0.0 } else { *amount })
+}
+
+/// A row of the output, as written for a single client.
+#[derive(Serialize)]
+struct ClientRecord {
+    client: u16,
+    #[serde(serialize_with = "serialize_amount")]
+    available: f64,
+    #[serde(serialize_with = "serialize_amount")]
+    held: f64,
+    #[serde(serialize_with = "serialize_amount")]
+    total: f64,
+    locked: Locked,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lifetime_disputes: Option<u32>,
 }
 
-impl Default for PaymentEngine {
-    fn default() -> Self {
+impl ClientRecord {
+    fn new(client: &Client, output: &OutputConfig) -> Self {
         Self {
-            clients: HashMap::new(),
-            executed_transactions: HashMap::new(),
+            client: client.client,
+            available: client.available,
+            held: client.held,
+            total: client.total,
+            locked: Locked(client.locked, output.locked_format),
+            lifetime_disputes: output.extended.then_some(client.lifetime_disputes),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct InputConfig {
+    /// File to write rows that could not be read to, with their line number and reason.
+    dead_letter: Option<String>,
+    /// Accept rows whose number of fields differs from the header.
+    flexible: bool,
+}
+
+#[derive(Debug, Default)]
+struct OutputConfig {
+    format: OutputFormat,
+    locked_format: LockedFormat,
+    /// Add risk signals to the output, such as the number of disputes a client ever had.
+    extended: bool,
+}
+
+/// What to do when a resolve or chargeback would release more than the client has held.
+/// Resolves and chargebacks only settle the same client's disputes, so valid input never
+/// gets here; this is a backstop.
+#[derive(Debug, Default, Clone, Copy)]
+enum NegativeHeldPolicy {
+    /// Release only what is still held, clamping `held` at zero.
+    #[default]
+    Clamp,
+    /// Reject the transition, leaving balances and dispute state untouched.
+    Strict,
+}
+
+/// Shortfalls of `held` smaller than this are rounding error and are clamped silently.
+const HELD_EPSILON: f64 = 1e-9;
+
+impl NegativeHeldPolicy {
+    /// Returns how much of `amount` can be released from `held` for transaction `tx`, or
+    /// `None` if the release is rejected.
+    fn release(
+        &self,
+        held: f64,
+        amount: f64,
+        tx: u32,
+        on_anomaly: AnomalyPolicy,
+        stats: &mut Stats,
+    ) -> Option<f64> {
+        let remaining = held - amount;
+        if remaining >= 0.0 {
+            return Some(amount);
+        }
+        if remaining > -HELD_EPSILON {
+            // floating point drift from earlier disputes, not a real shortfall
+            return Some(held);
+        }
+
+        stats.record(on_anomaly, Anomaly::NegativeHeld(tx));
+        match self {
+            NegativeHeldPolicy::Clamp => Some(held),
+            NegativeHeldPolicy::Strict => None,
+        }
+    }
+}
+
+/// What the engine does on running into an unusual state, such as a balance overflowing.
+/// Whatever the policy, such states are counted and the counts are reported on stderr once
+/// processing is done.
+#[derive(Debug, Default, Clone, Copy)]
+enum AnomalyPolicy {
+    /// Carry on, leaving the anomaly to the counts reported at the end.
+    #[default]
+    Skip,
+    /// Carry on, reporting the anomaly on stderr as it happens.
+    Warn,
+    /// Stop processing by panicking, for deployments that would rather fail than guess.
+    Panic,
+}
+
+impl AnomalyPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "skip" => Some(AnomalyPolicy::Skip),
+            "warn" => Some(AnomalyPolicy::Warn),
+            "panic" => Some(AnomalyPolicy::Panic),
+            _ => None,
+        }
+    }
+}
+
+/// An unexpected condition met while applying the transaction with the given id.
+#[derive(Debug, Clone, Copy)]
+enum Anomaly {
+    NegativeHeld(u32),
+    DoubleDispute(u32),
+    NegativeAvailable(u32),
+    Overflow(u32),
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Anomaly::NegativeHeld(tx) => {
+                write!(f, "transaction {} would drive held funds negative", tx)
+            }
+            Anomaly::DoubleDispute(tx) => write!(f, "transaction {} is already disputed", tx),
+            Anomaly::NegativeAvailable(tx) => {
+                write!(
+                    f,
+                    "dispute of transaction {} drives available funds negative",
+                    tx
+                )
+            }
+            Anomaly::Overflow(tx) => write!(f, "transaction {} overflows a balance", tx),
+        }
+    }
+}
+
+/// What a partial resolve does when its amount differs from what is still held for the
+/// disputed transaction.
+#[derive(Debug, Default, Clone, Copy)]
+enum ResolveMismatchPolicy {
+    /// Reject resolves asking for more than is held; smaller ones release their amount.
+    #[default]
+    Reject,
+    /// Clamp resolves asking for more than is held to what is held; smaller ones release
+    /// their amount.
+    Clamp,
+    /// Release everything still held whenever the amount does not match.
+    ReleaseRemaining,
+}
+
+impl ResolveMismatchPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "reject" => Some(ResolveMismatchPolicy::Reject),
+            "clamp" => Some(ResolveMismatchPolicy::Clamp),
+            "release" => Some(ResolveMismatchPolicy::ReleaseRemaining),
+            _ => None,
+        }
+    }
+
+    /// Returns how much a resolve for `requested` releases of the `remaining` held funds,
+    /// or `None` if it is rejected.
+    fn amount(&self, requested: f64, remaining: f64) -> Option<f64> {
+        if (requested - remaining).abs() <= HELD_EPSILON {
+            return Some(remaining);
+        }
+
+        match self {
+            ResolveMismatchPolicy::Reject if requested > remaining => None,
+            ResolveMismatchPolicy::Reject | ResolveMismatchPolicy::Clamp => {
+                Some(requested.min(remaining))
+            }
+            ResolveMismatchPolicy::ReleaseRemaining => Some(remaining),
+        }
+    }
+}
+
+/// What merging engines does with a client present in both.
+#[derive(Debug, Default, Clone, Copy)]
+enum DuplicateClientPolicy {
+    /// Fail the merge.
+    #[default]
+    Error,
+    /// Sum the balances and counters, keeping the client locked if either copy is.
+    Sum,
+}
+
+/// Policies that change how the engine treats otherwise valid transactions.
+#[derive(Debug, Default, Clone)]
+struct Config {
+    /// Apply zero-amount deposits without recording them in `executed_transactions`.
+    /// Disputing such a deposit would only hold zero, so there is nothing worth keeping.
+    skip_zero_deposits: bool,
+    /// Ignore disputes on zero-amount deposits, leaving the deposit undisputed. Such a
+    /// dispute would hold nothing and only move the deposit through the state machine.
+    ignore_zero_disputes: bool,
+    /// Stop processing at the first transaction whose id exceeds this one, leaving a
+    /// snapshot of balances as of that transaction. This assumes transaction ids are
+    /// monotonic in input order; dispute, resolve and chargeback rows reference earlier
+    /// ids, so they never end processing early.
+    as_of: Option<u32>,
+    negative_held: NegativeHeldPolicy,
+    /// Let a resolve carrying an amount release only that much of the disputed funds. The
+    /// dispute stays open until everything held for the transaction has been released. A
+    /// resolve with a zero or missing amount releases whatever remains.
+    partial_resolve: bool,
+    /// How partial resolves that do not match the remaining held funds are treated.
+    resolve_mismatch: ResolveMismatchPolicy,
+    /// Number of engines clients are spread over by id, each processing in its own thread.
+    /// Anything below two processes everything in a single engine.
+    shards: usize,
+    /// Record what happens to every row carrying this transaction id, to explain it later.
+    explain: Option<u32>,
+    duplicate_clients: DuplicateClientPolicy,
+    /// Lock any account whose total a withdrawal takes below this. A withdrawal never takes
+    /// `total` below zero, so only positive thresholds have an effect; totals only go negative
+    /// through chargebacks, which lock the account anyway.
+    lock_below: Option<f64>,
+    /// Hold back resolves and chargebacks arriving before the dispute they settle, and apply
+    /// them in arrival order right after that dispute. One that never sees its dispute stays
+    /// in memory until the end of the input, so adversarial input can grow the buffer
+    /// without bound.
+    buffer_settlements: bool,
+    /// Keep at most this many transactions in `executed_transactions` per engine, evicting
+    /// the least recently used one that is not under dispute. Disputes on an evicted
+    /// transaction are rejected as unknown. Disputed transactions are never evicted, so the
+    /// map can still exceed the cap when enough of them are open at once. Resolves and
+    /// chargebacks held back by `buffer_settlements` are dropped along with their transaction.
+    max_transactions: Option<usize>,
+    /// Output only clients changed by rows from the first transaction id above this mark
+    /// onwards, for incremental syncs against output of a run up to the mark. Like `as_of`,
+    /// this assumes transaction ids are monotonic in input order, so disputes, resolves and
+    /// chargebacks count as past the mark once a later id has been seen.
+    since_tx: Option<u32>,
+    on_anomaly: AnomalyPolicy,
+}
+
+/// Counters for anomalies seen while processing, reported once processing is done.
+#[derive(Debug, Default)]
+struct Stats {
+    /// Resolves and chargebacks that would have driven `held` below zero.
+    negative_held: u64,
+    /// Disputes on a transaction that was already under dispute.
+    double_disputes: u64,
+    /// Transactions evicted from `executed_transactions` under `max_transactions`.
+    evictions: u64,
+    /// Disputes on funds that were already spent, leaving available funds negative.
+    negative_available: u64,
+    /// Transactions leaving a balance that is no longer a finite number.
+    overflows: u64,
+}
+
+impl Stats {
+    fn record(&mut self, policy: AnomalyPolicy, anomaly: Anomaly) {
+        match anomaly {
+            Anomaly::NegativeHeld(_) => self.negative_held += 1,
+            Anomaly::DoubleDispute(_) => self.double_disputes += 1,
+            Anomaly::NegativeAvailable(_) => self.negative_available += 1,
+            Anomaly::Overflow(_) => self.overflows += 1,
+        }
+
+        match policy {
+            AnomalyPolicy::Skip => {}
+            AnomalyPolicy::Warn => eprintln!("WARNING: {}", anomaly),
+            AnomalyPolicy::Panic => panic!("anomaly: {}", anomaly),
+        }
+    }
+}
+
+/// When each executed transaction was last used, oldest first, for `max_transactions`.
+/// Transactions under dispute cannot be evicted and are left out until they are settled,
+/// so the oldest entry is always the one to evict.
+#[derive(Debug, Default)]
+struct Recency {
+    clock: u64,
+    order: BTreeMap<u64, u32>,
+    stamps: HashMap<u32, u64>,
+}
+
+impl Recency {
+    fn touch(&mut self, tx: u32) {
+        self.clock += 1;
+        if let Some(stamp) = self.stamps.insert(tx, self.clock) {
+            self.order.remove(&stamp);
+        }
+        self.order.insert(self.clock, tx);
+    }
+
+    fn remove(&mut self, tx: u32) {
+        if let Some(stamp) = self.stamps.remove(&tx) {
+            self.order.remove(&stamp);
         }
     }
 }
 
+#[derive(Default)]
+struct PaymentEngine {
+    config: Config,
+    input: InputConfig,
+    output: OutputConfig,
+    stats: Stats,
+    /// Ordered by id, so output is sorted and engines merge with a single sorted merge.
+    clients: BTreeMap<u16, Client>,
+    executed_transactions: HashMap<u32, Transaction>,
+    /// Resolves and chargebacks waiting for a dispute, by the transaction they reference.
+    pending_settlements: HashMap<u32, Vec<Transaction>>,
+    recency: Recency,
+    /// A transaction id above `since_tx` has been processed.
+    past_mark: bool,
+    explanation: Vec<Event>,
+}
+
 impl PaymentEngine {
     fn process_transaction(&mut self, transaction: Transaction) {
+        let (kind, client, tx, amount) = (
+            transaction.kind,
+            transaction.client,
+            transaction.tx,
+            transaction.amount,
+        );
+        let result = self.apply(transaction);
+        if result.is_ok() {
+            if let Some(client) = self.clients.get_mut(&client) {
+                client.applied = true;
+            }
+        }
+
+        if self.config.explain == Some(tx) {
+            self.explanation.push(Event {
+                kind,
+                client,
+                amount,
+                result,
+            });
+        }
+        if let Some(mark) = self.config.since_tx {
+            self.past_mark |= tx > mark;
+            if self.past_mark && result.is_ok() {
+                if let Some(client) = self.clients.get_mut(&client) {
+                    client.changed = true;
+                }
+            }
+        }
+        if let Some(max) = self.config.max_transactions {
+            if result.is_ok() {
+                match self.executed_transactions.get(&tx) {
+                    Some(transaction) if transaction.state == TransactionState::Dispute => {
+                        self.recency.remove(tx)
+                    }
+                    Some(_) => self.recency.touch(tx),
+                    None => {}
+                }
+                self.evict(max);
+            }
+        }
+        if result.is_ok() && matches!(kind, TransactionType::Dispute) {
+            for settlement in self.pending_settlements.remove(&tx).unwrap_or_default() {
+                self.process_transaction(settlement);
+            }
+        }
+    }
+
+    /// Evicts least recently used transactions not under dispute until at most `max` remain,
+    /// along with any resolves and chargebacks still waiting for them.
+    fn evict(&mut self, max: usize) {
+        while self.executed_transactions.len() > max {
+            let Some((_, tx)) = self.recency.order.pop_first() else {
+                return;
+            };
+
+            self.recency.stamps.remove(&tx);
+            self.pending_settlements.remove(&tx);
+            if self.executed_transactions.remove(&tx).is_some() {
+                self.stats.evictions += 1;
+            }
+        }
+    }
+
+    fn apply(&mut self, transaction: Transaction) -> Result<(), Rejection> {
+        let tx = transaction.tx;
         let client = self.clients.entry(transaction.client).or_insert(Client {
             client: transaction.client,
             available: 0.0,
             held: 0.0,
             total: 0.0,
             locked: false,
+            lifetime_disputes: 0,
+            changed: false,
+            applied: false,
         });
 
-        if client.locked || transaction.amount < 0.0 {
-            return;
+        if client.locked {
+            return Err(Rejection::Locked);
+        }
+        if transaction.amount < 0.0 {
+            return Err(Rejection::NegativeAmount);
         }
 
         match transaction.kind {
@@ -86,156 +696,1109 @@ impl PaymentEngine {
                 client.available += transaction.amount;
                 client.total += transaction.amount;
 
+                if transaction.amount == 0.0 && self.config.skip_zero_deposits {
+                    return Ok(());
+                }
+
                 self.executed_transactions
                     .insert(transaction.tx, transaction);
             }
             TransactionType::Withdrawal => {
-                if client.available >= transaction.amount {
-                    client.available -= transaction.amount;
-                    client.total -= transaction.amount;
+                if client.available < transaction.amount {
+                    return Err(Rejection::InsufficientFunds);
+                }
 
-                    self.executed_transactions
-                        .insert(transaction.tx, transaction);
+                client.available -= transaction.amount;
+                client.total -= transaction.amount;
+                if self
+                    .config
+                    .lock_below
+                    .is_some_and(|limit| client.total < limit)
+                {
+                    client.locked = true;
                 }
+
+                self.executed_transactions
+                    .insert(transaction.tx, transaction);
             }
             TransactionType::Dispute => {
-                if let Some(reference_transaction) =
+                let Some(reference_transaction) =
                     self.executed_transactions.get_mut(&transaction.tx)
+                else {
+                    return Err(Rejection::UnknownTransaction);
+                };
+
+                if reference_transaction.client != transaction.client {
+                    // client is trying to dispute a transaction that does not belong to them
+                    return Err(Rejection::ForeignTransaction);
+                }
+
+                if self.config.ignore_zero_disputes
+                    && matches!(reference_transaction.kind, TransactionType::Deposit)
+                    && reference_transaction.amount == 0.0
                 {
-                    if reference_transaction.client != transaction.client {
-                        // client is trying to dispute a transaction that does not belong to them
-                        return;
-                    }
+                    return Err(Rejection::NothingToHold);
+                }
 
-                    match (&reference_transaction.state, &reference_transaction.kind) {
-                        (
-                            TransactionState::None,
-                            TransactionType::Deposit | TransactionType::Withdrawal,
-                        ) => {
-                            client.held += reference_transaction.amount;
-                            client.available -= reference_transaction.amount;
-                            reference_transaction.state = TransactionState::Dispute;
-                        }
-                        _ => {}
-                    }
+                // A transaction is in at most one dispute at a time, so funds are held for it
+                // only once. Resolved and charged back transactions cannot be disputed again.
+                if reference_transaction.state == TransactionState::Dispute {
+                    self.stats
+                        .record(self.config.on_anomaly, Anomaly::DoubleDispute(tx));
+                    return Err(Rejection::AlreadyDisputed);
+                }
+
+                let (
+                    TransactionState::None,
+                    TransactionType::Deposit | TransactionType::Withdrawal,
+                ) = (&reference_transaction.state, &reference_transaction.kind)
+                else {
+                    return Err(Rejection::InvalidState);
+                };
+
+                client.held += reference_transaction.amount;
+                client.available -= reference_transaction.amount;
+                reference_transaction.held = reference_transaction.amount;
+                reference_transaction.state = TransactionState::Dispute;
+                client.lifetime_disputes += 1;
+                if client.available < -HELD_EPSILON {
+                    self.stats
+                        .record(self.config.on_anomaly, Anomaly::NegativeAvailable(tx));
                 }
             }
             TransactionType::Resolve => {
-                if let Some(reference_transaction) =
+                let Some(reference_transaction) =
                     self.executed_transactions.get_mut(&transaction.tx)
+                else {
+                    return Err(Rejection::UnknownTransaction);
+                };
+
+                if reference_transaction.client != transaction.client {
+                    return Err(Rejection::ForeignTransaction);
+                }
+                if reference_transaction.state == TransactionState::None
+                    && self.config.buffer_settlements
                 {
-                    if reference_transaction.state == TransactionState::Dispute {
-                        client.held -= reference_transaction.amount;
-                        client.available += reference_transaction.amount;
-                        reference_transaction.state = TransactionState::Resolve;
-                    }
+                    self.pending_settlements
+                        .entry(transaction.tx)
+                        .or_default()
+                        .push(transaction);
+                    return Err(Rejection::Pending);
+                }
+                if reference_transaction.state != TransactionState::Dispute {
+                    return Err(Rejection::InvalidState);
+                }
+
+                let amount = if self.config.partial_resolve && transaction.amount > 0.0 {
+                    self.config
+                        .resolve_mismatch
+                        .amount(transaction.amount, reference_transaction.held)
+                        .ok_or(Rejection::ExceedsHeld)?
+                } else {
+                    reference_transaction.held
+                };
+
+                let released = self
+                    .config
+                    .negative_held
+                    .release(
+                        client.held,
+                        amount,
+                        tx,
+                        self.config.on_anomaly,
+                        &mut self.stats,
+                    )
+                    .ok_or(Rejection::NegativeHeld)?;
+
+                client.held -= released;
+                client.available += released;
+                reference_transaction.held -= amount;
+                if reference_transaction.held <= HELD_EPSILON {
+                    reference_transaction.held = 0.0;
+                    reference_transaction.state = TransactionState::Resolve;
                 }
             }
             TransactionType::Chargeback => {
-                if let Some(reference_transaction) =
+                let Some(reference_transaction) =
                     self.executed_transactions.get_mut(&transaction.tx)
+                else {
+                    return Err(Rejection::UnknownTransaction);
+                };
+
+                if reference_transaction.client != transaction.client {
+                    return Err(Rejection::ForeignTransaction);
+                }
+                if reference_transaction.state == TransactionState::None
+                    && self.config.buffer_settlements
                 {
-                    if reference_transaction.state == TransactionState::Dispute {
-                        client.held -= reference_transaction.amount;
-                        client.total -= reference_transaction.amount;
-                        client.locked = true;
-                        reference_transaction.state = TransactionState::Chargeback;
-                    }
+                    self.pending_settlements
+                        .entry(transaction.tx)
+                        .or_default()
+                        .push(transaction);
+                    return Err(Rejection::Pending);
                 }
+                if reference_transaction.state != TransactionState::Dispute {
+                    return Err(Rejection::InvalidState);
+                }
+
+                let released = self
+                    .config
+                    .negative_held
+                    .release(
+                        client.held,
+                        reference_transaction.held,
+                        tx,
+                        self.config.on_anomaly,
+                        &mut self.stats,
+                    )
+                    .ok_or(Rejection::NegativeHeld)?;
+
+                // a chargeback locks the account whatever the balance
+                client.held -= released;
+                client.total -= released;
+                client.locked = true;
+                reference_transaction.held = 0.0;
+                reference_transaction.state = TransactionState::Chargeback;
             }
         }
+
+        if !(client.available.is_finite() && client.held.is_finite() && client.total.is_finite()) {
+            self.stats
+                .record(self.config.on_anomaly, Anomaly::Overflow(tx));
+        }
+        Ok(())
     }
 
-    fn process_transactions(&mut self, rx: Receiver<Transaction>) {
-        while let Ok(transaction) = rx.recv() {
+    /// Processes transactions pulled from `next` until it returns `None`. Any pull-based
+    /// source (a queue consumer, a database cursor) can drive the engine this way.
+    fn run_with<F>(&mut self, mut next: F)
+    where
+        F: FnMut() -> Option<Transaction>,
+    {
+        while let Some(transaction) = next() {
+            if self
+                .config
+                .as_of
+                .is_some_and(|as_of| transaction.tx > as_of)
+            {
+                break;
+            }
             self.process_transaction(transaction);
         }
     }
 
-    fn read_input(reader: &mut Reader<File>, tx: Sender<Transaction>) {
-        for result in reader.deserialize() {
-            match result {
-                Ok(record) => {
-                    tx.send(record).expect("Failed to send transaction.");
+    fn process_transactions(&mut self, rx: Receiver<Transaction>) {
+        self.run_with(|| rx.recv().ok());
+    }
+
+    /// Reads transactions from `reader` and sends each to the shard owning its client.
+    /// Reading stops at the first transaction past `as_of`, so every shard sees the same
+    /// prefix of the input. Rows that cannot be read are dropped, and written with their
+    /// line number and reason to `dead_letter` if one is given.
+    fn read_input<R: Read, W: Write>(
+        reader: &mut csv::Reader<R>,
+        shards: Vec<Sender<Transaction>>,
+        mut dead_letter: Option<&mut csv::Writer<W>>,
+        as_of: Option<u32>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let headers = reader.headers()?.clone();
+        for result in reader.records() {
+            let parsed = match result {
+                Ok(record) => parse_record(&record, &headers)
+                    .map_err(|err| (record.position().map_or(0, |position| position.line()), err)),
+                Err(err) => Err((
+                    err.position().map_or(0, |position| position.line()),
+                    RowError::Malformed(err.to_string()),
+                )),
+            };
+
+            match parsed {
+                Ok(transaction) => {
+                    if as_of.is_some_and(|as_of| transaction.tx > as_of) {
+                        break;
+                    }
+                    let shard = &shards[transaction.client as usize % shards.len()];
+                    if shard.send(transaction).is_err() {
+                        // processing has stopped early, nothing left to read for
+                        break;
+                    }
+                }
+                Err((line, err)) => {
+                    if let Some(dead_letter) = dead_letter.as_mut() {
+                        dead_letter.write_record([line.to_string(), err.to_string()])?;
+                    }
                 }
-                Err(_) => {}
             }
         }
+
+        if let Some(dead_letter) = dead_letter {
+            dead_letter.flush()?;
+        }
+        Ok(())
     }
 
     fn start(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
-        let mut reader = csv::ReaderBuilder::new()
-            .trim(csv::Trim::All)
-            .from_path(path)?;
+        let reader = reader_builder(self.input.flexible).from_path(path)?;
+        self.run_reader(reader)
+    }
 
-        let (tx, rx) = std::sync::mpsc::channel();
+    /// Reads and processes every transaction of `reader`. This engine processes the first
+    /// shard; any further shards get an engine of their own that is merged back in once
+    /// all input has been processed.
+    fn run_reader<R: Read + Send>(
+        &mut self,
+        mut reader: csv::Reader<R>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut dead_letter = match &self.input.dead_letter {
+            Some(path) => {
+                let mut writer = csv::Writer::from_path(path)?;
+                writer.write_record(["line", "error"])?;
+                Some(writer)
+            }
+            None => None,
+        };
+
+        let as_of = self.config.as_of;
+        let mut shards: Vec<PaymentEngine> = (1..self.config.shards)
+            .map(|_| PaymentEngine {
+                config: self.config.clone(),
+                ..Default::default()
+            })
+            .collect();
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..=shards.len())
+            .map(|_| std::sync::mpsc::channel())
+            .unzip();
 
-        thread::scope(|scope| {
-            let process_handle = scope.spawn(|| self.process_transactions(rx));
-            let read_handle = scope.spawn(|| Self::read_input(&mut reader, tx));
+        let result = thread::scope(|scope| {
+            let engines = std::iter::once(&mut *self).chain(shards.iter_mut());
+            let process_handles: Vec<_> = engines
+                .zip(receivers)
+                .map(|(engine, rx)| scope.spawn(move || engine.process_transactions(rx)))
+                .collect();
+            let read_handle =
+                scope.spawn(|| Self::read_input(&mut reader, senders, dead_letter.as_mut(), as_of));
 
-            read_handle.join().unwrap();
-            process_handle.join().unwrap();
+            // an engine panics under `AnomalyPolicy::Panic`, pass that on as it is
+            let read_result = read_handle
+                .join()
+                .unwrap_or_else(|panic| resume_unwind(panic));
+            for process_handle in process_handles {
+                if let Err(panic) = process_handle.join() {
+                    resume_unwind(panic);
+                }
+            }
+            read_result
         });
 
+        result.map_err(|err| err as Box<dyn Error>)?;
+        for shard in shards {
+            self.merge(shard)?;
+        }
         Ok(())
     }
 
-    fn save_output(self) -> Result<(), Box<dyn Error>> {
-        let mut writer = csv::WriterBuilder::new().from_writer(std::io::stdout());
-        for client in self.clients.values() {
-            writer.serialize(client)?;
+    /// Merges the clients, transactions and counters of `other` into this engine. Both
+    /// client maps are ordered by id, so they are combined in a single sorted merge.
+    /// Shards never share a client, but engines from separate runs can; see
+    /// [`DuplicateClientPolicy`].
+    fn merge(&mut self, mut other: PaymentEngine) -> Result<(), Box<dyn Error>> {
+        let duplicates: Vec<u16> = other
+            .clients
+            .keys()
+            .filter(|id| self.clients.contains_key(id))
+            .copied()
+            .collect();
+        if let (Some(id), DuplicateClientPolicy::Error) =
+            (duplicates.first(), self.config.duplicate_clients)
+        {
+            return Err(format!("client {} is present in both merged engines", id).into());
+        }
+        for id in duplicates {
+            let duplicate = other.clients.remove(&id).unwrap();
+            let client = self.clients.get_mut(&id).unwrap();
+            client.available += duplicate.available;
+            client.held += duplicate.held;
+            client.total += duplicate.total;
+            client.locked |= duplicate.locked;
+            client.lifetime_disputes += duplicate.lifetime_disputes;
+            client.changed |= duplicate.changed;
+            client.applied |= duplicate.applied;
         }
-        writer.flush()?;
-        Ok(())
-    }
-}
 
-fn app() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <input_file>", args[0]);
-        exit(1)
+        self.clients.append(&mut other.clients);
+        self.executed_transactions
+            .extend(other.executed_transactions);
+        self.pending_settlements.extend(other.pending_settlements);
+        self.stats.negative_held += other.stats.negative_held;
+        self.stats.double_disputes += other.stats.double_disputes;
+        self.stats.evictions += other.stats.evictions;
+        self.stats.negative_available += other.stats.negative_available;
+        self.stats.overflows += other.stats.overflows;
+        self.explanation.append(&mut other.explanation);
+        Ok(())
     }
 
-    let mut payment_engine = PaymentEngine::default();
-    payment_engine.start(&args[1])?;
-    payment_engine.save_output()?;
+    /// Describes what happened to transaction `tx`: every row referencing it, whether it
+    /// was applied, and the state it ended up in.
+    fn write_explanation<W: Write>(&self, tx: u32, mut output: W) -> std::io::Result<()> {
+        if self.explanation.is_empty() {
+            return writeln!(output, "tx {}: not in the input", tx);
+        }
 
-    Ok(())
-}
+        writeln!(output, "tx {}:", tx)?;
+        for event in &self.explanation {
+            write!(output, "  {} by client {}", event.kind, event.client)?;
+            if let TransactionType::Deposit | TransactionType::Withdrawal = event.kind {
+                write!(output, " of {}", event.amount)?;
+            }
+            match event.result {
+                Ok(()) => writeln!(output, ": applied")?,
+                Err(rejection) => writeln!(output, ": rejected, {}", rejection)?,
+            }
+        }
 
-fn main() {
-    let now = Instant::now();
-    if let Err(err) = app() {
-        println!("ERROR: {}", err);
-        exit(1);
+        match self.executed_transactions.get(&tx) {
+            Some(transaction) => {
+                let state = match transaction.state {
+                    TransactionState::None => "never disputed",
+                    TransactionState::Dispute => "disputed",
+                    TransactionState::Resolve => "resolved",
+                    TransactionState::Chargeback => "charged back",
+                };
+                writeln!(
+                    output,
+                    "  affected client {}, {}",
+                    transaction.client, state
+                )
+            }
+            // zero-amount deposits under `skip_zero_deposits` are applied without being kept
+            None if self.explanation.iter().any(|event| event.result.is_ok()) => {
+                writeln!(output, "  applied but not kept")
+            }
+            None => writeln!(output, "  never applied"),
+        }
     }
-    let elapsed = now.elapsed();
-    _ = elapsed;
-    // eprintln!("Elapsed time: {}ms", elapsed.as_millis());
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{Client, PaymentEngine, Transaction, TransactionState, TransactionType};
-    use std::collections::VecDeque;
+    fn write_output<W: Write + Send>(&self, output: W) -> Result<(), Box<dyn Error>> {
+        match self.output.format {
+            OutputFormat::Csv => self.write_clients(output, self.output_clients(), &self.output),
+            OutputFormat::Parquet => self.write_parquet(output),
+        }
+    }
 
-    #[test]
-    fn test_deposit() {
-        let mut payment_engine = PaymentEngine::default();
+    fn write_parquet<W: Write + Send>(&self, output: W) -> Result<(), Box<dyn Error>> {
+        let clients = self.output_clients();
+        let amount_type = DataType::Decimal128(PARQUET_PRECISION, PARQUET_SCALE);
+        let amounts = |amount: fn(&Client) -> f64| -> Result<ArrayRef, ArrowError> {
+            let scale = 10f64.powi(PARQUET_SCALE as i32);
+            let values = clients
+                .iter()
+                .map(|client| match amount(client) {
+                    value if value.is_finite() => Ok((value * scale).round() as i128),
+                    value => Err(ArrowError::InvalidArgumentError(format!(
+                        "client {} has an amount of {}",
+                        client.client, value
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
 
-        let tx = Transaction {
-            kind: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: 5.0,
-            state: TransactionState::None,
+            // `as` saturates, so amounts beyond the precision have to be caught here
+            let array = Decimal128Array::from(values)
+                .with_precision_and_scale(PARQUET_PRECISION, PARQUET_SCALE)?;
+            array.validate_decimal_precision(PARQUET_PRECISION)?;
+            Ok(Arc::new(array))
         };
 
-        assert_eq!(payment_engine.clients.contains_key(&tx.client), false);
-
-        payment_engine.process_transaction(tx);
+        let mut fields = vec![
+            Field::new("client", DataType::UInt16, false),
+            Field::new("available", amount_type.clone(), false),
+            Field::new("held", amount_type.clone(), false),
+            Field::new("total", amount_type, false),
+            Field::new("locked", DataType::Boolean, false),
+        ];
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt16Array::from_iter_values(
+                clients.iter().map(|client| client.client),
+            )),
+            amounts(|client| client.available)?,
+            amounts(|client| client.held)?,
+            amounts(|client| client.total)?,
+            Arc::new(BooleanArray::from(
+                clients
+                    .iter()
+                    .map(|client| client.locked)
+                    .collect::<Vec<_>>(),
+            )),
+        ];
+        if self.output.extended {
+            fields.push(Field::new("lifetime_disputes", DataType::UInt32, false));
+            columns.push(Arc::new(UInt32Array::from_iter_values(
+                clients.iter().map(|client| client.lifetime_disputes),
+            )));
+        }
+
+        let schema = Arc::new(Schema::new(fields));
+        let batch = RecordBatch::try_new(schema.clone(), columns)?;
+        let mut writer = ArrowWriter::try_new(output, schema, None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        Ok(())
+    }
+
+    /// Clients to output, which under `since_tx` are only those changed past the mark.
+    fn output_clients(&self) -> Vec<&Client> {
+        self.clients
+            .values()
+            .filter(|client| self.config.since_tx.is_none() || client.changed)
+            .collect()
+    }
+
+    fn write_clients<'a, W: Write>(
+        &self,
+        output: W,
+        clients: impl IntoIterator<Item = &'a Client>,
+        format: &OutputConfig,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::WriterBuilder::new().from_writer(output);
+        for client in clients {
+            writer.serialize(ClientRecord::new(client, format))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// SHA-256 over the balances in the default output format, sorted by client, as hex.
+    /// Output options do not change it, so two runs can be compared by hash alone.
+    fn state_hash(&self) -> Result<String, Box<dyn Error>> {
+        let mut canonical = Vec::new();
+        self.write_clients(
+            &mut canonical,
+            self.clients.values(),
+            &OutputConfig::default(),
+        )?;
+
+        Ok(Sha256::digest(&canonical)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    }
+}
+
+/// Sums over the rows of a whole input, computed in constant memory for monitoring
+/// pipelines that do not need per-client output. Without per-client state nothing can be
+/// checked against balances, so these are sums over rows rather than what the engine would
+/// apply: withdrawals count in full and locked accounts are not honoured. Only rows with a
+/// negative amount, which the engine always rejects, are left out. Held funds would need
+/// every disputed amount remembered, as dispute rows carry none, so they are not reported.
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct Aggregates {
+    #[serde(serialize_with = "serialize_amount")]
+    deposit_rows_total: f64,
+    #[serde(serialize_with = "serialize_amount")]
+    withdrawal_rows_total: f64,
+    chargeback_rows: u64,
+}
+
+impl Aggregates {
+    fn add(&mut self, transaction: &Transaction) {
+        if transaction.amount < 0.0 {
+            return;
+        }
+
+        match transaction.kind {
+            TransactionType::Deposit => self.deposit_rows_total += transaction.amount,
+            TransactionType::Withdrawal => self.withdrawal_rows_total += transaction.amount,
+            TransactionType::Chargeback => self.chargeback_rows += 1,
+            TransactionType::Dispute | TransactionType::Resolve => {}
+        }
+    }
+
+    /// Totals the rows of `reader` up to the first transaction past `as_of`. Rows that
+    /// cannot be read are skipped, as they are when processing.
+    fn read<R: Read>(reader: &mut csv::Reader<R>, as_of: Option<u32>) -> Result<Self, csv::Error> {
+        let mut aggregates = Aggregates::default();
+        let headers = reader.headers()?.clone();
+        for record in reader.records().flatten() {
+            let Ok(transaction) = parse_record(&record, &headers) else {
+                continue;
+            };
+            if as_of.is_some_and(|as_of| transaction.tx > as_of) {
+                break;
+            }
+            aggregates.add(&transaction);
+        }
+        Ok(aggregates)
+    }
+}
+
+/// With `flexible`, rows may have more or fewer fields than the header. Extra fields are
+/// ignored and rows missing a field the engine needs are still dropped.
+fn reader_builder(flexible: bool) -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder.trim(csv::Trim::All).flexible(flexible);
+    builder
+}
+
+#[derive(Debug, PartialEq)]
+enum ViolationKind {
+    /// No deposit or withdrawal with this id appears anywhere in the input.
+    Unknown,
+    /// The referenced transaction only appears further down, on the given line.
+    Later(u64),
+    /// The referenced transaction belongs to the given client.
+    Foreign(u16),
+}
+
+/// A dispute, resolve or chargeback that does not reference an earlier deposit or
+/// withdrawal of the same client.
+#[derive(Debug, PartialEq)]
+struct Violation {
+    line: u64,
+    tx: u32,
+    kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: tx {} ", self.line, self.tx)?;
+        match self.kind {
+            ViolationKind::Unknown => write!(f, "does not exist"),
+            ViolationKind::Later(line) => write!(f, "only appears later, on line {}", line),
+            ViolationKind::Foreign(client) => write!(f, "belongs to client {}", client),
+        }
+    }
+}
+
+/// Checks that every dispute, resolve and chargeback references a deposit or withdrawal
+/// that appears earlier in the input and belongs to the same client. The first pass
+/// indexes deposits and withdrawals, the second checks the references against them.
+/// Rows that cannot be parsed are skipped, as they would be when processing.
+fn validate_references<R: Read + Seek>(
+    input: &mut R,
+    flexible: bool,
+) -> Result<Vec<Violation>, Box<dyn Error>> {
+    let mut executed: HashMap<u32, (u64, u16)> = HashMap::new();
+    {
+        let mut reader = reader_builder(flexible).from_reader(&mut *input);
+        let headers = reader.headers()?.clone();
+        for record in reader.records() {
+            let Ok(record) = record else {
+                continue;
+            };
+            let Ok(transaction) = parse_record(&record, &headers) else {
+                continue;
+            };
+            if let TransactionType::Deposit | TransactionType::Withdrawal = transaction.kind {
+                let line = record.position().map_or(0, |position| position.line());
+                executed
+                    .entry(transaction.tx)
+                    .or_insert((line, transaction.client));
+            }
+        }
+    }
+
+    input.seek(SeekFrom::Start(0))?;
+
+    let mut violations = Vec::new();
+    let mut reader = reader_builder(flexible).from_reader(input);
+    let headers = reader.headers()?.clone();
+    for record in reader.records() {
+        let Ok(record) = record else {
+            continue;
+        };
+        let Ok(transaction) = parse_record(&record, &headers) else {
+            continue;
+        };
+        if let TransactionType::Deposit | TransactionType::Withdrawal = transaction.kind {
+            continue;
+        }
+
+        let line = record.position().map_or(0, |position| position.line());
+        let kind = match executed.get(&transaction.tx) {
+            None => ViolationKind::Unknown,
+            Some(&(executed_line, _)) if executed_line > line => {
+                ViolationKind::Later(executed_line)
+            }
+            Some(&(_, client)) if client != transaction.client => ViolationKind::Foreign(client),
+            Some(_) => continue,
+        };
+        violations.push(Violation {
+            line,
+            tx: transaction.tx,
+            kind,
+        });
+    }
+
+    Ok(violations)
+}
+
+const OPTIONS: &str = "\
+Options:
+  --as-of <tx>              stop after transaction <tx>, assuming ids are monotonic
+  --skip-zero-deposits      apply zero-amount deposits without keeping them for disputes
+  --ignore-zero-disputes    ignore disputes on zero-amount deposits
+  --strict-held             reject resolves/chargebacks that would drive held funds negative
+  --partial-resolve         let resolves release only the amount they carry
+  --resolve-mismatch <p>    partial resolves not matching held funds: reject (default), clamp or release
+  --format <format>         write balances as csv (default) or parquet
+  --locked-format <format>  write locked as true-false (default), 1-0 or yes-no
+  --validate                check dispute references before processing
+  --dead-letter <file>      write rows that could not be read to <file>
+  --extended                add lifetime dispute counts to the output
+  --empty-exit              exit with code 10 when no client had a transaction applied
+  --shards <n>              spread clients over <n> engines processing in parallel
+  --explain <tx>            print what happened to transaction <tx> instead of balances
+  --flexible                accept rows with more or fewer fields than the header
+  --lock-below <amount>     lock accounts a withdrawal leaves with a total below <amount>
+  --aggregate               print sums over the input rows instead of per-client balances
+  --buffer-settlements      hold resolves/chargebacks arriving before their dispute until it does
+  --max-transactions <n>    keep at most <n> undisputed transactions for later disputes
+  --since-tx <tx>           output only clients changed by transactions after <tx>
+  --on-anomaly <p>          on unexpected states: skip (default), warn as they happen or panic
+  --hash                    print a SHA-256 of the final balances to stderr
+  --sum-duplicates          sum balances of clients present in more than one input file
+
+Each input file is processed by an engine of its own and the results are merged.";
+
+struct Args {
+    inputs: Vec<String>,
+    as_of: Option<u32>,
+    strict_held: bool,
+    ignore_zero_disputes: bool,
+    skip_zero_deposits: bool,
+    partial_resolve: bool,
+    resolve_mismatch: ResolveMismatchPolicy,
+    format: OutputFormat,
+    locked_format: LockedFormat,
+    validate: bool,
+    dead_letter: Option<String>,
+    extended: bool,
+    empty_exit: bool,
+    shards: usize,
+    explain: Option<u32>,
+    flexible: bool,
+    sum_duplicates: bool,
+    lock_below: Option<f64>,
+    aggregate: bool,
+    hash: bool,
+    buffer_settlements: bool,
+    max_transactions: Option<usize>,
+    since_tx: Option<u32>,
+    on_anomaly: AnomalyPolicy,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut inputs = Vec::new();
+        let mut as_of = None;
+        let mut strict_held = false;
+        let mut ignore_zero_disputes = false;
+        let mut skip_zero_deposits = false;
+        let mut partial_resolve = false;
+        let mut resolve_mismatch = None;
+        let mut format = OutputFormat::default();
+        let mut locked_format = None;
+        let mut validate = false;
+        let mut dead_letter = None;
+        let mut extended = false;
+        let mut empty_exit = false;
+        let mut shards = 1;
+        let mut explain = None;
+        let mut flexible = false;
+        let mut sum_duplicates = false;
+        let mut lock_below = None;
+        let mut aggregate = false;
+        let mut hash = false;
+        let mut buffer_settlements = false;
+        let mut max_transactions = None;
+        let mut since_tx = None;
+        let mut on_anomaly = None;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--as-of" => {
+                    let value = args.next().ok_or("--as-of requires a transaction id")?;
+                    let tx = value
+                        .parse()
+                        .map_err(|_| format!("invalid transaction id: {}", value))?;
+                    as_of = Some(tx);
+                }
+                "--since-tx" => {
+                    let value = args.next().ok_or("--since-tx requires a transaction id")?;
+                    let tx = value
+                        .parse()
+                        .map_err(|_| format!("invalid transaction id: {}", value))?;
+                    since_tx = Some(tx);
+                }
+                "--strict-held" => strict_held = true,
+                "--ignore-zero-disputes" => ignore_zero_disputes = true,
+                "--skip-zero-deposits" => skip_zero_deposits = true,
+                "--partial-resolve" => partial_resolve = true,
+                "--resolve-mismatch" => {
+                    let value = args.next().ok_or("--resolve-mismatch requires a policy")?;
+                    resolve_mismatch =
+                        Some(ResolveMismatchPolicy::parse(value).ok_or_else(|| {
+                            format!("invalid resolve mismatch policy: {}", value)
+                        })?);
+                }
+                "--on-anomaly" => {
+                    let value = args.next().ok_or("--on-anomaly requires a policy")?;
+                    on_anomaly = Some(
+                        AnomalyPolicy::parse(value)
+                            .ok_or_else(|| format!("invalid anomaly policy: {}", value))?,
+                    );
+                }
+                "--validate" => validate = true,
+                "--extended" => extended = true,
+                "--flexible" => flexible = true,
+                "--sum-duplicates" => sum_duplicates = true,
+                "--aggregate" => aggregate = true,
+                "--hash" => hash = true,
+                "--buffer-settlements" => buffer_settlements = true,
+                "--lock-below" => {
+                    let value = args.next().ok_or("--lock-below requires an amount")?;
+                    let limit = value
+                        .parse()
+                        .ok()
+                        .filter(|limit: &f64| limit.is_finite())
+                        .ok_or_else(|| format!("invalid amount: {}", value))?;
+                    lock_below = Some(limit);
+                }
+                "--empty-exit" => empty_exit = true,
+                "--max-transactions" => {
+                    let value = args.next().ok_or("--max-transactions requires a count")?;
+                    let max = value
+                        .parse()
+                        .map_err(|_| format!("invalid transaction count: {}", value))?;
+                    max_transactions = Some(max);
+                }
+                "--explain" => {
+                    let value = args.next().ok_or("--explain requires a transaction id")?;
+                    let tx = value
+                        .parse()
+                        .map_err(|_| format!("invalid transaction id: {}", value))?;
+                    explain = Some(tx);
+                }
+                "--shards" => {
+                    let value = args.next().ok_or("--shards requires a count")?;
+                    shards = value
+                        .parse()
+                        .ok()
+                        .filter(|&shards| shards > 0)
+                        .ok_or_else(|| format!("invalid shard count: {}", value))?;
+                }
+                "--dead-letter" => {
+                    let value = args.next().ok_or("--dead-letter requires a file")?;
+                    dead_letter = Some(value.clone());
+                }
+                "--format" => {
+                    let value = args.next().ok_or("--format requires a format")?;
+                    format = OutputFormat::parse(value)
+                        .ok_or_else(|| format!("invalid output format: {}", value))?;
+                }
+                "--locked-format" => {
+                    let value = args.next().ok_or("--locked-format requires a format")?;
+                    locked_format = Some(
+                        LockedFormat::parse(value)
+                            .ok_or_else(|| format!("invalid locked format: {}", value))?,
+                    );
+                }
+                _ if arg.starts_with("--") => return Err(format!("unknown option: {}", arg)),
+                _ => inputs.push(arg.clone()),
+            }
+        }
+
+        if inputs.is_empty() {
+            return Err("missing input file".to_string());
+        }
+        if inputs.len() > 1 && dead_letter.is_some() {
+            return Err("--dead-letter takes a single input file".to_string());
+        }
+        if shards > 1 && since_tx.is_some() {
+            // each shard would only see the mark passed in its own rows
+            return Err("--since-tx cannot be combined with --shards".to_string());
+        }
+        if shards > 1 && max_transactions.is_some() {
+            // the limit would apply per shard, so eviction would depend on the shard count
+            return Err("--max-transactions cannot be combined with --shards".to_string());
+        }
+        if aggregate {
+            // totals are computed without an engine and written as CSV, so only `--as-of`,
+            // `--flexible` and `--validate` apply
+            for (flag, set) in [
+                ("--strict-held", strict_held),
+                ("--ignore-zero-disputes", ignore_zero_disputes),
+                ("--skip-zero-deposits", skip_zero_deposits),
+                ("--partial-resolve", partial_resolve),
+                ("--resolve-mismatch", resolve_mismatch.is_some()),
+                ("--format parquet", format == OutputFormat::Parquet),
+                ("--locked-format", locked_format.is_some()),
+                ("--dead-letter", dead_letter.is_some()),
+                ("--extended", extended),
+                ("--empty-exit", empty_exit),
+                ("--shards", shards > 1),
+                ("--explain", explain.is_some()),
+                ("--sum-duplicates", sum_duplicates),
+                ("--lock-below", lock_below.is_some()),
+                ("--hash", hash),
+                ("--buffer-settlements", buffer_settlements),
+                ("--max-transactions", max_transactions.is_some()),
+                ("--since-tx", since_tx.is_some()),
+                ("--on-anomaly", on_anomaly.is_some()),
+            ] {
+                if set {
+                    return Err(format!("{} cannot be combined with --aggregate", flag));
+                }
+            }
+        }
+        if shards > 1 && explain.is_some() {
+            // a shard cannot tell a reference to another shard's client from an unknown one
+            return Err("--explain cannot be combined with --shards".to_string());
+        }
+        if format == OutputFormat::Parquet && locked_format.is_some() {
+            // Parquet has a boolean type for locked
+            return Err("--locked-format cannot be combined with --format parquet".to_string());
+        }
+        if format == OutputFormat::Parquet && explain.is_some() {
+            // the explanation is plain text, not a Parquet file
+            return Err("--explain cannot be combined with --format parquet".to_string());
+        }
+        if max_transactions.is_some() && explain.is_some() {
+            // an evicted transaction would read as never applied
+            return Err("--explain cannot be combined with --max-transactions".to_string());
+        }
+        if explain.is_some() {
+            // the explanation replaces the balances these shape or check
+            for (flag, set) in [
+                ("--since-tx", since_tx.is_some()),
+                ("--extended", extended),
+                ("--locked-format", locked_format.is_some()),
+                ("--empty-exit", empty_exit),
+            ] {
+                if set {
+                    return Err(format!("{} cannot be combined with --explain", flag));
+                }
+            }
+        }
+
+        Ok(Self {
+            inputs,
+            as_of,
+            strict_held,
+            ignore_zero_disputes,
+            skip_zero_deposits,
+            partial_resolve,
+            resolve_mismatch: resolve_mismatch.unwrap_or_default(),
+            format,
+            locked_format: locked_format.unwrap_or_default(),
+            validate,
+            dead_letter,
+            extended,
+            empty_exit,
+            shards,
+            explain,
+            flexible,
+            sum_duplicates,
+            lock_below,
+            aggregate,
+            hash,
+            buffer_settlements,
+            max_transactions,
+            since_tx,
+            on_anomaly: on_anomaly.unwrap_or_default(),
+        })
+    }
+}
+
+/// Exit code for `--empty-exit` when no client written had a transaction applied.
+const EMPTY_EXIT_CODE: i32 = 10;
+
+/// Runs the command line in `args`, writing balances to `output`, and returns the exit code.
+fn app<W: Write + Send>(args: &[String], output: W) -> Result<i32, Box<dyn Error>> {
+    let parsed = match Args::parse(&args[1..]) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("Usage: {} [options] <input_file>...", args[0]);
+            eprintln!("{}", OPTIONS);
+            return Ok(1);
+        }
+    };
+
+    if parsed.validate {
+        for input in &parsed.inputs {
+            let violations = validate_references(&mut File::open(input)?, parsed.flexible)?;
+            for violation in &violations {
+                eprintln!("{}: {}", input, violation);
+            }
+            if !violations.is_empty() {
+                return Err(format!(
+                    "{} has {} invalid dispute references",
+                    input,
+                    violations.len()
+                )
+                .into());
+            }
+        }
+    }
+
+    let mut payment_engine = PaymentEngine::default();
+    payment_engine.config.as_of = parsed.as_of;
+    payment_engine.config.ignore_zero_disputes = parsed.ignore_zero_disputes;
+    payment_engine.config.skip_zero_deposits = parsed.skip_zero_deposits;
+    payment_engine.config.partial_resolve = parsed.partial_resolve;
+    payment_engine.config.resolve_mismatch = parsed.resolve_mismatch;
+    payment_engine.config.shards = parsed.shards;
+    payment_engine.config.explain = parsed.explain;
+    payment_engine.config.lock_below = parsed.lock_below;
+    payment_engine.config.buffer_settlements = parsed.buffer_settlements;
+    payment_engine.config.max_transactions = parsed.max_transactions;
+    payment_engine.config.since_tx = parsed.since_tx;
+    payment_engine.config.on_anomaly = parsed.on_anomaly;
+    payment_engine.input.dead_letter = parsed.dead_letter;
+    payment_engine.input.flexible = parsed.flexible;
+    payment_engine.output.format = parsed.format;
+    payment_engine.output.locked_format = parsed.locked_format;
+    payment_engine.output.extended = parsed.extended;
+    if parsed.strict_held {
+        payment_engine.config.negative_held = NegativeHeldPolicy::Strict;
+    }
+    if parsed.sum_duplicates {
+        payment_engine.config.duplicate_clients = DuplicateClientPolicy::Sum;
+    }
+
+    if parsed.aggregate {
+        let mut writer = csv::Writer::from_writer(output);
+        for input in &parsed.inputs {
+            let mut reader = reader_builder(parsed.flexible).from_path(input)?;
+            writer.serialize(Aggregates::read(&mut reader, parsed.as_of)?)?;
+        }
+        writer.flush()?;
+        return Ok(0);
+    }
+    payment_engine.start(&parsed.inputs[0])?;
+
+    for input in &parsed.inputs[1..] {
+        let mut other = PaymentEngine {
+            config: payment_engine.config.clone(),
+            ..Default::default()
+        };
+        other.input.flexible = parsed.flexible;
+        other.start(input)?;
+        payment_engine.merge(other)?;
+    }
+
+    if payment_engine.stats.negative_held > 0 {
+        eprintln!(
+            "WARNING: {} resolves/chargebacks tried to drive held funds negative",
+            payment_engine.stats.negative_held
+        );
+    }
+    if payment_engine.stats.double_disputes > 0 {
+        eprintln!(
+            "WARNING: {} disputes on already disputed transactions were ignored",
+            payment_engine.stats.double_disputes
+        );
+    }
+    if payment_engine.stats.negative_available > 0 {
+        eprintln!(
+            "WARNING: {} disputes drove available funds negative",
+            payment_engine.stats.negative_available
+        );
+    }
+    if payment_engine.stats.overflows > 0 {
+        eprintln!(
+            "WARNING: {} transactions overflowed a balance",
+            payment_engine.stats.overflows
+        );
+    }
+    if payment_engine.stats.evictions > 0 {
+        eprintln!(
+            "WARNING: {} transactions were evicted and can no longer be disputed",
+            payment_engine.stats.evictions
+        );
+    }
+    let pending: usize = payment_engine
+        .pending_settlements
+        .values()
+        .map(Vec::len)
+        .sum();
+    if pending > 0 {
+        eprintln!(
+            "WARNING: {} resolves/chargebacks never saw their dispute",
+            pending
+        );
+    }
+    match parsed.explain {
+        Some(tx) => payment_engine.write_explanation(tx, output)?,
+        None => payment_engine.write_output(output)?,
+    }
+    if parsed.hash {
+        eprintln!("sha256: {}", payment_engine.state_hash()?);
+    }
+
+    // a rejected row still creates its client, so only clients with applied rows count
+    if parsed.empty_exit
+        && !payment_engine
+            .output_clients()
+            .iter()
+            .any(|client| client.applied)
+    {
+        return Ok(EMPTY_EXIT_CODE);
+    }
+    Ok(0)
+}
+
+fn main() {
+    let now = Instant::now();
+    let args: Vec<String> = std::env::args().collect();
+    let code = match app(&args, std::io::stdout()) {
+        Ok(code) => code,
+        Err(err) => {
+            println!("ERROR: {}", err);
+            1
+        }
+    };
+    let elapsed = now.elapsed();
+    _ = elapsed;
+    // eprintln!("Elapsed time: {}ms", elapsed.as_millis());
+    exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generator::Generator;
+    use crate::{
+        app, reader_builder, validate_references, Aggregates, AnomalyPolicy, Args, Client,
+        DuplicateClientPolicy, LockedFormat, NegativeHeldPolicy, OutputFormat, PaymentEngine,
+        Rejection, ResolveMismatchPolicy, Stats, Transaction, TransactionState, TransactionType,
+        Violation, ViolationKind, EMPTY_EXIT_CODE,
+    };
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Decimal128Type, UInt16Type};
+    use arrow_array::RecordBatch;
+    use bytes::Bytes;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use proptest::prelude::*;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_deposit() {
+        let mut payment_engine = PaymentEngine::default();
+
+        let tx = Transaction {
+            kind: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: 5.0,
+            state: TransactionState::None,
+            held: 0.0,
+        };
+
+        assert!(!payment_engine.clients.contains_key(&tx.client));
+
+        payment_engine.process_transaction(tx);
 
         let client = payment_engine.clients.get(&1).expect("Client not found");
 
@@ -254,6 +1817,9 @@ mod tests {
                 held: 0.0,
                 total: 5.0,
                 locked: false,
+                lifetime_disputes: 0,
+                changed: false,
+                applied: false,
             },
         );
 
@@ -263,6 +1829,7 @@ mod tests {
             tx: 1,
             amount: 5.0,
             state: TransactionState::None,
+            held: 0.0,
         };
 
         let client = payment_engine.clients.get(&1).expect("Client not found");
@@ -287,6 +1854,9 @@ mod tests {
                 held: 0.0,
                 total: 5.0,
                 locked: false,
+                lifetime_disputes: 0,
+                changed: false,
+                applied: false,
             },
         );
 
@@ -296,6 +1866,7 @@ mod tests {
             tx: 1,
             amount: 10.0,
             state: TransactionState::None,
+            held: 0.0,
         };
 
         let client = payment_engine.clients.get(&1).expect("Client not found");
@@ -320,6 +1891,9 @@ mod tests {
                 held: 0.0,
                 total: 5.0,
                 locked: true,
+                lifetime_disputes: 0,
+                changed: false,
+                applied: false,
             },
         );
 
@@ -329,6 +1903,7 @@ mod tests {
             tx: 1,
             amount: 5.0,
             state: TransactionState::None,
+            held: 0.0,
         };
 
         let client = payment_engine.clients.get(&1).expect("Client not found");
@@ -353,6 +1928,7 @@ mod tests {
                 tx: 1,
                 amount: 5.0,
                 state: TransactionState::None,
+                held: 0.0,
             },
             Transaction {
                 kind: TransactionType::Withdrawal,
@@ -360,6 +1936,7 @@ mod tests {
                 tx: 2,
                 amount: 5.0,
                 state: TransactionState::None,
+                held: 0.0,
             },
             Transaction {
                 kind: TransactionType::Dispute,
@@ -367,6 +1944,7 @@ mod tests {
                 tx: 1,
                 amount: 0.0,
                 state: TransactionState::None,
+                held: 0.0,
             },
             Transaction {
                 kind: TransactionType::Chargeback,
@@ -374,6 +1952,7 @@ mod tests {
                 tx: 1,
                 amount: 0.0,
                 state: TransactionState::None,
+                held: 0.0,
             },
         ]);
 
@@ -407,7 +1986,7 @@ mod tests {
         assert_eq!(client.available, -5.0);
         assert_eq!(client.held, 0.0);
         assert_eq!(client.total, -5.0);
-        assert_eq!(client.locked, true);
+        assert!(client.locked);
         assert_eq!(
             payment_engine
                 .executed_transactions
@@ -429,6 +2008,7 @@ mod tests {
                 tx: 1,
                 amount: 5.0,
                 state: TransactionState::None,
+                held: 0.0,
             },
             Transaction {
                 kind: TransactionType::Resolve,
@@ -436,6 +2016,7 @@ mod tests {
                 tx: 1,
                 amount: 0.0,
                 state: TransactionState::None,
+                held: 0.0,
             },
             Transaction {
                 kind: TransactionType::Dispute,
@@ -443,6 +2024,7 @@ mod tests {
                 tx: 1,
                 amount: 0.0,
                 state: TransactionState::None,
+                held: 0.0,
             },
             Transaction {
                 kind: TransactionType::Resolve,
@@ -450,6 +2032,7 @@ mod tests {
                 tx: 1,
                 amount: 0.0,
                 state: TransactionState::None,
+                held: 0.0,
             },
             Transaction {
                 kind: TransactionType::Dispute,
@@ -457,6 +2040,7 @@ mod tests {
                 tx: 1,
                 amount: 0.0,
                 state: TransactionState::None,
+                held: 0.0,
             },
         ]);
 
@@ -513,6 +2097,7 @@ mod tests {
                 tx: 1,
                 amount: 5.0,
                 state: TransactionState::None,
+                held: 0.0,
             },
             Transaction {
                 kind: TransactionType::Dispute,
@@ -520,6 +2105,7 @@ mod tests {
                 tx: 1,
                 amount: 0.0,
                 state: TransactionState::None,
+                held: 0.0,
             },
         ]);
 
@@ -528,7 +2114,7 @@ mod tests {
         assert_eq!(client.available, 5.0);
         assert_eq!(client.held, 0.0);
         assert_eq!(client.total, 5.0);
-        assert_eq!(client.locked, false);
+        assert!(!client.locked);
         assert_eq!(
             payment_engine
                 .executed_transactions
@@ -538,4 +2124,1335 @@ mod tests {
             TransactionState::None
         );
     }
+
+    #[test]
+    fn test_ignore_zero_disputes() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.config.ignore_zero_disputes = true;
+
+        payment_engine.process_transaction(Transaction {
+            kind: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: 0.0,
+            state: TransactionState::None,
+            held: 0.0,
+        });
+        let dispute = Transaction {
+            kind: TransactionType::Dispute,
+            client: 1,
+            tx: 1,
+            amount: 0.0,
+            state: TransactionState::None,
+            held: 0.0,
+        };
+        assert_eq!(payment_engine.apply(dispute), Err(Rejection::NothingToHold));
+
+        assert_eq!(
+            payment_engine.executed_transactions[&1].state,
+            TransactionState::None
+        );
+        assert_eq!(payment_engine.clients[&1].lifetime_disputes, 0);
+    }
+
+    #[test]
+    fn test_skip_zero_deposits() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.config.skip_zero_deposits = true;
+
+        let mut transactions = VecDeque::from(vec![
+            Transaction {
+                kind: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: 0.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Deposit,
+                client: 1,
+                tx: 2,
+                amount: 5.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+        ]);
+
+        payment_engine.process_transaction(transactions.pop_front().unwrap());
+        assert!(!payment_engine.executed_transactions.contains_key(&1));
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.available, 0.0);
+        assert_eq!(client.total, 0.0);
+
+        payment_engine.process_transaction(transactions.pop_front().unwrap());
+        assert!(payment_engine.executed_transactions.contains_key(&2));
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.available, 5.0);
+        assert_eq!(client.total, 5.0);
+    }
+
+    #[test]
+    fn test_as_of() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.config.as_of = Some(2);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        for transaction in [
+            Transaction {
+                kind: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: 5.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Deposit,
+                client: 2,
+                tx: 2,
+                amount: 3.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: 0.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Withdrawal,
+                client: 2,
+                tx: 3,
+                amount: 1.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Deposit,
+                client: 3,
+                tx: 4,
+                amount: 1.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+        ] {
+            tx.send(transaction).unwrap();
+        }
+        drop(tx);
+
+        payment_engine.process_transactions(rx);
+
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.available, 0.0);
+        assert_eq!(client.held, 5.0);
+        let client = payment_engine.clients.get(&2).expect("Client not found");
+        assert_eq!(client.available, 3.0);
+        assert_eq!(client.total, 3.0);
+        assert!(!payment_engine.clients.contains_key(&3));
+    }
+
+    #[test]
+    fn test_negative_held() {
+        for (policy, held, available, total) in [
+            (NegativeHeldPolicy::Clamp, 0.0, 5.0, 5.0),
+            (NegativeHeldPolicy::Strict, 2.0, 3.0, 5.0),
+        ] {
+            let mut payment_engine = PaymentEngine::default();
+            payment_engine.config.negative_held = policy;
+
+            payment_engine.process_transaction(Transaction {
+                kind: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: 5.0,
+                state: TransactionState::None,
+                held: 0.0,
+            });
+            payment_engine.process_transaction(Transaction {
+                kind: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: 0.0,
+                state: TransactionState::None,
+                held: 0.0,
+            });
+
+            // simulate held drifting below the disputed amount
+            payment_engine.clients.get_mut(&1).unwrap().held = 2.0;
+            payment_engine.clients.get_mut(&1).unwrap().available = 3.0;
+
+            payment_engine.process_transaction(Transaction {
+                kind: TransactionType::Resolve,
+                client: 1,
+                tx: 1,
+                amount: 0.0,
+                state: TransactionState::None,
+                held: 0.0,
+            });
+
+            let client = payment_engine.clients.get(&1).expect("Client not found");
+            assert_eq!(client.held, held);
+            assert_eq!(client.available, available);
+            assert_eq!(client.total, total);
+            assert_eq!(payment_engine.stats.negative_held, 1);
+        }
+    }
+
+    #[test]
+    fn test_foreign_settlement() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,1.0
+dispute,1,1,0
+chargeback,2,1,0
+resolve,2,1,0
+";
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine
+            .run_reader(reader_builder(false).from_reader(input.as_bytes()))
+            .unwrap();
+
+        assert_eq!(payment_engine.clients[&1].held, 5.0);
+        assert!(!payment_engine.clients[&2].locked);
+        assert_eq!(
+            payment_engine.executed_transactions[&1].state,
+            TransactionState::Dispute
+        );
+        assert_eq!(payment_engine.stats.negative_held, 0);
+    }
+
+    #[test]
+    fn test_held_drift() {
+        // holding 0.3 and 0.4 then releasing 0.3 leaves slightly less than 0.4 held
+        let held = 0.3 + 0.4 - 0.3;
+        assert!(held < 0.4);
+
+        let mut stats = Stats::default();
+        assert_eq!(
+            NegativeHeldPolicy::Strict.release(held, 0.4, 1, AnomalyPolicy::Skip, &mut stats),
+            Some(held)
+        );
+        assert_eq!(stats.negative_held, 0);
+        assert_eq!(
+            NegativeHeldPolicy::Strict.release(0.3, 0.4, 1, AnomalyPolicy::Skip, &mut stats),
+            None
+        );
+        assert_eq!(stats.negative_held, 1);
+    }
+
+    fn transaction_strategy() -> impl Strategy<Value = Transaction> {
+        (0..5u8, 1..4u16, 1..16u32, 0..100_000u32).prop_map(|(kind, client, tx, amount)| {
+            Transaction {
+                kind: match kind {
+                    0 => TransactionType::Deposit,
+                    1 => TransactionType::Withdrawal,
+                    2 => TransactionType::Dispute,
+                    3 => TransactionType::Resolve,
+                    _ => TransactionType::Chargeback,
+                },
+                client,
+                tx,
+                amount: amount as f64 / 1000.0,
+                state: TransactionState::None,
+                held: 0.0,
+            }
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_held_never_negative(
+            transactions in prop::collection::vec(transaction_strategy(), 0..200),
+            strict in any::<bool>(),
+        ) {
+            let mut payment_engine = PaymentEngine::default();
+            if strict {
+                payment_engine.config.negative_held = NegativeHeldPolicy::Strict;
+            }
+
+            for transaction in transactions {
+                payment_engine.process_transaction(transaction);
+                for client in payment_engine.clients.values() {
+                    prop_assert!(client.held >= 0.0, "held went negative: {:?}", client);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_locked_format() {
+        for (format, locked, unlocked) in [
+            (LockedFormat::TrueFalse, "true", "false"),
+            (LockedFormat::OneZero, "1", "0"),
+            (LockedFormat::YesNo, "yes", "no"),
+        ] {
+            let mut payment_engine = PaymentEngine::default();
+            payment_engine.output.locked_format = format;
+            for client in [1, 2] {
+                payment_engine.clients.insert(
+                    client,
+                    Client {
+                        client,
+                        available: 5.0,
+                        held: 0.0,
+                        total: 5.0,
+                        locked: client == 1,
+                        lifetime_disputes: 0,
+                        changed: false,
+                        applied: false,
+                    },
+                );
+            }
+
+            let mut output = Vec::new();
+            payment_engine.write_output(&mut output).unwrap();
+
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                format!(
+                    "client,available,held,total,locked\n1,5.0,0.0,5.0,{}\n2,5.0,0.0,5.0,{}\n",
+                    locked, unlocked
+                )
+            );
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_generated_stream(seed in any::<u64>()) {
+            let mut payment_engine = PaymentEngine::default();
+            for transaction in Generator::new(seed, 10).take(2_000) {
+                payment_engine.process_transaction(transaction);
+            }
+
+            prop_assert_eq!(payment_engine.stats.negative_held, 0);
+            for client in payment_engine.clients.values() {
+                prop_assert!(client.available >= -1e-6, "negative available: {:?}", client);
+                prop_assert!(client.held >= 0.0, "negative held: {:?}", client);
+                prop_assert!(
+                    (client.total - client.available - client.held).abs() < 1e-6,
+                    "total does not add up: {:?}",
+                    client
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_partial_resolve_over_release() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.config.partial_resolve = true;
+
+        let mut transactions = VecDeque::from(vec![
+            Transaction {
+                kind: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: 10.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: 0.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Resolve,
+                client: 1,
+                tx: 1,
+                amount: 6.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Resolve,
+                client: 1,
+                tx: 1,
+                amount: 6.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Resolve,
+                client: 1,
+                tx: 1,
+                amount: 4.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+        ]);
+
+        payment_engine.process_transaction(transactions.pop_front().unwrap());
+        payment_engine.process_transaction(transactions.pop_front().unwrap());
+        payment_engine.process_transaction(transactions.pop_front().unwrap());
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.available, 6.0);
+        assert_eq!(client.held, 4.0);
+        let reference_transaction = payment_engine.executed_transactions.get(&1).unwrap();
+        assert_eq!(reference_transaction.state, TransactionState::Dispute);
+        assert_eq!(reference_transaction.held, 4.0);
+
+        // 6.0 more would release 12.0 of the 10.0 held
+        payment_engine.process_transaction(transactions.pop_front().unwrap());
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.available, 6.0);
+        assert_eq!(client.held, 4.0);
+        let reference_transaction = payment_engine.executed_transactions.get(&1).unwrap();
+        assert_eq!(reference_transaction.state, TransactionState::Dispute);
+        assert_eq!(reference_transaction.held, 4.0);
+
+        payment_engine.process_transaction(transactions.pop_front().unwrap());
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.available, 10.0);
+        assert_eq!(client.held, 0.0);
+        assert_eq!(client.total, 10.0);
+        let reference_transaction = payment_engine.executed_transactions.get(&1).unwrap();
+        assert_eq!(reference_transaction.state, TransactionState::Resolve);
+        assert_eq!(reference_transaction.held, 0.0);
+    }
+
+    #[test]
+    fn test_validate_references() {
+        let mut input = std::io::Cursor::new(
+            "type,client,tx,amount
+deposit,1,1,5.0
+dispute,1,1,0.0
+dispute,1,2,0.0
+deposit,1,2,5.0
+dispute,2,1,0.0
+resolve,1,3,0.0
+dispute,1,4,0.0,extra
+",
+        );
+
+        // the ragged last row is skipped rather than aborting the check
+        let violations = validate_references(&mut input, false).unwrap();
+
+        assert_eq!(
+            violations,
+            vec![
+                Violation {
+                    line: 4,
+                    tx: 2,
+                    kind: ViolationKind::Later(5),
+                },
+                Violation {
+                    line: 6,
+                    tx: 1,
+                    kind: ViolationKind::Foreign(1),
+                },
+                Violation {
+                    line: 7,
+                    tx: 3,
+                    kind: ViolationKind::Unknown,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_negative_zero_output() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.clients.insert(
+            1,
+            Client {
+                client: 1,
+                available: -0.0,
+                held: -0.0,
+                total: -0.0,
+                locked: true,
+                lifetime_disputes: 0,
+                changed: false,
+                applied: false,
+            },
+        );
+
+        let mut output = Vec::new();
+        payment_engine.write_output(&mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,0.0,0.0,0.0,true\n"
+        );
+    }
+
+    #[test]
+    fn test_run_with() {
+        let mut payment_engine = PaymentEngine::default();
+
+        let mut transactions = vec![
+            Transaction {
+                kind: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: 5.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Withdrawal,
+                client: 1,
+                tx: 2,
+                amount: 2.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+        ]
+        .into_iter();
+
+        payment_engine.run_with(|| transactions.next());
+
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.available, 3.0);
+        assert_eq!(client.total, 3.0);
+        assert_eq!(payment_engine.executed_transactions.len(), 2);
+    }
+
+    #[test]
+    fn test_resolve_mismatch() {
+        for (policy, amount, available, held, state) in [
+            (
+                ResolveMismatchPolicy::Reject,
+                12.0,
+                0.0,
+                10.0,
+                TransactionState::Dispute,
+            ),
+            (
+                ResolveMismatchPolicy::Reject,
+                4.0,
+                4.0,
+                6.0,
+                TransactionState::Dispute,
+            ),
+            (
+                ResolveMismatchPolicy::Clamp,
+                12.0,
+                10.0,
+                0.0,
+                TransactionState::Resolve,
+            ),
+            (
+                ResolveMismatchPolicy::Clamp,
+                4.0,
+                4.0,
+                6.0,
+                TransactionState::Dispute,
+            ),
+            (
+                ResolveMismatchPolicy::ReleaseRemaining,
+                12.0,
+                10.0,
+                0.0,
+                TransactionState::Resolve,
+            ),
+            (
+                ResolveMismatchPolicy::ReleaseRemaining,
+                4.0,
+                10.0,
+                0.0,
+                TransactionState::Resolve,
+            ),
+        ] {
+            let mut payment_engine = PaymentEngine::default();
+            payment_engine.config.partial_resolve = true;
+            payment_engine.config.resolve_mismatch = policy;
+
+            for (kind, amount) in [
+                (TransactionType::Deposit, 10.0),
+                (TransactionType::Dispute, 0.0),
+                (TransactionType::Resolve, amount),
+            ] {
+                payment_engine.process_transaction(Transaction {
+                    kind,
+                    client: 1,
+                    tx: 1,
+                    amount,
+                    state: TransactionState::None,
+                    held: 0.0,
+                });
+            }
+
+            let client = payment_engine.clients.get(&1).expect("Client not found");
+            assert_eq!(client.available, available, "{:?} {}", policy, amount);
+            assert_eq!(client.held, held, "{:?} {}", policy, amount);
+            assert_eq!(client.total, 10.0);
+            let reference_transaction = payment_engine.executed_transactions.get(&1).unwrap();
+            assert_eq!(
+                reference_transaction.state, state,
+                "{:?} {}",
+                policy, amount
+            );
+            assert_eq!(reference_transaction.held, held);
+        }
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,abc
+deposit,1,3,
+withdrawal,1,4,1.0
+deposit,1,5,NaN
+deposit,1,6,inf
+";
+        let mut reader = reader_builder(false).from_reader(input.as_bytes());
+        let mut dead_letter = csv::Writer::from_writer(Vec::new());
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        PaymentEngine::read_input(&mut reader, vec![tx], Some(&mut dead_letter), None).unwrap();
+
+        let transactions: Vec<_> = rx.iter().map(|transaction| transaction.tx).collect();
+        assert_eq!(transactions, vec![1, 4]);
+        assert_eq!(
+            String::from_utf8(dead_letter.into_inner().unwrap()).unwrap(),
+            "3,invalid amount 'abc'\n4,missing field amount\n6,invalid amount 'NaN'\n7,invalid amount 'inf'\n"
+        );
+    }
+
+    #[test]
+    fn test_dead_letter_short_row() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2
+deposit,1
+";
+        let mut reader = reader_builder(true).from_reader(input.as_bytes());
+        let mut dead_letter = csv::Writer::from_writer(Vec::new());
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        PaymentEngine::read_input(&mut reader, vec![tx], Some(&mut dead_letter), None).unwrap();
+
+        assert_eq!(rx.iter().count(), 1);
+        assert_eq!(
+            String::from_utf8(dead_letter.into_inner().unwrap()).unwrap(),
+            "3,missing field amount\n4,missing field tx\n"
+        );
+    }
+
+    #[test]
+    fn test_lifetime_disputes() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.output.extended = true;
+
+        for (kind, tx) in [
+            (TransactionType::Deposit, 1),
+            (TransactionType::Deposit, 2),
+            (TransactionType::Dispute, 1),
+            (TransactionType::Resolve, 1),
+            (TransactionType::Dispute, 2),
+            (TransactionType::Resolve, 2),
+        ] {
+            payment_engine.process_transaction(Transaction {
+                kind,
+                client: 1,
+                tx,
+                amount: 5.0,
+                state: TransactionState::None,
+                held: 0.0,
+            });
+        }
+
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.held, 0.0);
+        assert_eq!(client.lifetime_disputes, 2);
+
+        let mut output = Vec::new();
+        payment_engine.write_output(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,lifetime_disputes\n1,10.0,0.0,10.0,false,2\n"
+        );
+    }
+
+    #[test]
+    fn test_empty_exit() {
+        let input = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/input_rejected.csv");
+        let args = |flags: &[&str]| {
+            std::iter::once("app")
+                .chain(flags.iter().copied())
+                .chain(std::iter::once(input))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+
+        let mut output = Vec::new();
+        assert_eq!(app(&args(&[]), &mut output).unwrap(), 0);
+        assert!(output.is_empty());
+
+        let mut output = Vec::new();
+        assert_eq!(
+            app(&args(&["--empty-exit"]), &mut output).unwrap(),
+            EMPTY_EXIT_CODE
+        );
+        assert!(output.is_empty());
+
+        // every row is read but rejected by the engine, which still writes their clients
+        let args = [
+            "app",
+            "--empty-exit",
+            "test_data/input_rejected_by_engine.csv",
+        ];
+        let mut output = Vec::new();
+        assert_eq!(
+            app(&args.map(String::from), &mut output).unwrap(),
+            EMPTY_EXIT_CODE
+        );
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,0.0,0.0,0.0,false\n2,0.0,0.0,0.0,false\n"
+        );
+
+        // clients exist, but none changed past the mark
+        let args = [
+            "app",
+            "--empty-exit",
+            "--since-tx",
+            "100",
+            "test_data/input_2.csv",
+        ];
+        let mut output = Vec::new();
+        assert_eq!(
+            app(&args.map(String::from), &mut output).unwrap(),
+            EMPTY_EXIT_CODE
+        );
+        assert!(output.is_empty());
+    }
+
+    fn transaction(kind: TransactionType, tx: u32, amount: f64) -> Transaction {
+        Transaction {
+            kind,
+            client: 1,
+            tx,
+            amount,
+            state: TransactionState::None,
+            held: 0.0,
+        }
+    }
+
+    fn generated_input(seed: u64, clients: u16, transactions: usize) -> String {
+        let mut input = String::from("type,client,tx,amount\n");
+        for transaction in Generator::new(seed, clients).take(transactions) {
+            input.push_str(&format!(
+                "{},{},{},{}\n",
+                transaction.kind, transaction.client, transaction.tx, transaction.amount
+            ));
+        }
+        input
+    }
+
+    #[test]
+    fn test_sharded_output() {
+        let input = generated_input(7, 50, 20_000);
+
+        let outputs: Vec<Vec<u8>> = [1, 4]
+            .into_iter()
+            .map(|shards| {
+                let mut payment_engine = PaymentEngine::default();
+                payment_engine.config.shards = shards;
+                payment_engine
+                    .run_reader(reader_builder(false).from_reader(input.as_bytes()))
+                    .unwrap();
+
+                let mut output = Vec::new();
+                payment_engine.write_output(&mut output).unwrap();
+                output
+            })
+            .collect();
+
+        assert_eq!(String::from_utf8_lossy(&outputs[0]).lines().count(), 51);
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn test_explain() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+withdrawal,1,2,8.0
+dispute,2,1,0.0
+dispute,1,1,0.0
+chargeback,2,1,0.0
+chargeback,1,1,0.0
+";
+
+        let explain = |tx| {
+            let mut payment_engine = PaymentEngine::default();
+            payment_engine.config.explain = Some(tx);
+            payment_engine
+                .run_reader(reader_builder(false).from_reader(input.as_bytes()))
+                .unwrap();
+
+            let mut output = Vec::new();
+            payment_engine.write_explanation(tx, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            explain(2),
+            "tx 2:
+  withdrawal by client 1 of 8: rejected, insufficient available funds
+  never applied
+"
+        );
+        assert_eq!(
+            explain(1),
+            "tx 1:
+  deposit by client 1 of 5: applied
+  dispute by client 2: rejected, referenced transaction belongs to another client
+  dispute by client 1: applied
+  chargeback by client 2: rejected, referenced transaction belongs to another client
+  chargeback by client 1: applied
+  affected client 1, charged back
+"
+        );
+        assert_eq!(explain(3), "tx 3: not in the input\n");
+
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.config.explain = Some(1);
+        payment_engine.config.skip_zero_deposits = true;
+        payment_engine.process_transaction(transaction(TransactionType::Deposit, 1, 0.0));
+        let mut output = Vec::new();
+        payment_engine.write_explanation(1, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tx 1:
+  deposit by client 1 of 0: applied
+  applied but not kept
+"
+        );
+
+        for args in [
+            ["--shards", "2", "--explain", "1", "input.csv"],
+            ["--max-transactions", "1", "--explain", "1", "input.csv"],
+            ["--since-tx", "1", "--explain", "1", "input.csv"],
+            ["--locked-format", "1-0", "--explain", "1", "input.csv"],
+        ] {
+            assert!(Args::parse(&args.map(String::from)).is_err());
+        }
+        for args in [
+            ["--extended", "--explain", "1", "input.csv"],
+            ["--empty-exit", "--explain", "1", "input.csv"],
+        ] {
+            assert!(Args::parse(&args.map(String::from)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_flexible() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0,extra
+deposit,1,3
+withdrawal,1,4,1.0
+dispute,1,1
+";
+
+        for (flexible, available, held) in [(false, 4.0, 0.0), (true, 2.0, 5.0)] {
+            let mut payment_engine = PaymentEngine::default();
+            payment_engine
+                .run_reader(reader_builder(flexible).from_reader(input.as_bytes()))
+                .unwrap();
+
+            let client = payment_engine.clients.get(&1).expect("Client not found");
+            assert_eq!(client.available, available);
+            assert_eq!(client.held, held);
+            assert!(!payment_engine.executed_transactions.contains_key(&3));
+        }
+    }
+
+    #[test]
+    fn test_merge_duplicate_clients() {
+        let engine = |available, locked| {
+            let mut payment_engine = PaymentEngine::default();
+            payment_engine.config.duplicate_clients = DuplicateClientPolicy::Sum;
+            for client in [1, 2] {
+                payment_engine.clients.insert(
+                    client * available as u16,
+                    Client {
+                        client: client * available as u16,
+                        available,
+                        held: 1.0,
+                        total: available + 1.0,
+                        locked,
+                        lifetime_disputes: 1,
+                        changed: false,
+                        applied: false,
+                    },
+                );
+            }
+            payment_engine
+        };
+
+        // shares client 2
+        let mut payment_engine = engine(1.0, false);
+        payment_engine.merge(engine(2.0, true)).unwrap();
+
+        assert_eq!(
+            payment_engine.clients.keys().copied().collect::<Vec<_>>(),
+            vec![1, 2, 4]
+        );
+        let client = payment_engine.clients.get(&2).expect("Client not found");
+        assert_eq!(client.available, 3.0);
+        assert_eq!(client.held, 2.0);
+        assert_eq!(client.total, 5.0);
+        assert!(client.locked);
+        assert_eq!(client.lifetime_disputes, 2);
+
+        let mut payment_engine = engine(1.0, false);
+        payment_engine.config.duplicate_clients = DuplicateClientPolicy::Error;
+        assert!(payment_engine.merge(engine(2.0, true)).is_err());
+    }
+
+    #[test]
+    fn test_lock_below() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.config.lock_below = Some(2.0);
+
+        let mut transactions = VecDeque::from(vec![
+            Transaction {
+                kind: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: 5.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Withdrawal,
+                client: 1,
+                tx: 2,
+                amount: 2.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Withdrawal,
+                client: 1,
+                tx: 3,
+                amount: 2.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+            Transaction {
+                kind: TransactionType::Deposit,
+                client: 1,
+                tx: 4,
+                amount: 5.0,
+                state: TransactionState::None,
+                held: 0.0,
+            },
+        ]);
+
+        payment_engine.process_transaction(transactions.pop_front().unwrap());
+        payment_engine.process_transaction(transactions.pop_front().unwrap());
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.total, 3.0);
+        assert!(!client.locked);
+
+        payment_engine.process_transaction(transactions.pop_front().unwrap());
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.total, 1.0);
+        assert!(client.locked);
+
+        payment_engine.process_transaction(transactions.pop_front().unwrap());
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.total, 1.0);
+
+        // a dispute moves funds from available to held but leaves the total alone
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.config.lock_below = Some(2.0);
+        payment_engine.process_transaction(transaction(TransactionType::Deposit, 1, 5.0));
+        payment_engine.process_transaction(transaction(TransactionType::Dispute, 1, 0.0));
+        assert!(!payment_engine.clients[&1].locked);
+        payment_engine.process_transaction(transaction(TransactionType::Resolve, 1, 0.0));
+        let client = &payment_engine.clients[&1];
+        assert_eq!(client.available, 5.0);
+        assert_eq!(client.held, 0.0);
+        assert!(!client.locked);
+        assert_eq!(
+            payment_engine.executed_transactions[&1].state,
+            TransactionState::Resolve
+        );
+
+        for limit in ["nan", "inf", "-inf", "abc"] {
+            let args = ["--lock-below", limit, "input.csv"];
+            assert!(Args::parse(&args.map(String::from)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_aggregates() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,1.5
+dispute,1,1,
+dispute,2,2,
+chargeback,1,1,
+deposit,3,4,abc
+deposit,3,5,-1.0
+deposit,3,6,1.0,extra
+";
+        let mut reader = reader_builder(false).from_reader(input.as_bytes());
+        assert_eq!(
+            Aggregates::read(&mut reader, None).unwrap(),
+            Aggregates {
+                deposit_rows_total: 8.0,
+                withdrawal_rows_total: 1.5,
+                chargeback_rows: 1,
+            }
+        );
+
+        let aggregates = |as_of| {
+            let mut reader = reader_builder(false)
+                .from_path("test_data/input_2.csv")
+                .unwrap();
+            Aggregates::read(&mut reader, as_of).unwrap()
+        };
+        // rows count in full, including the withdrawal rejected for insufficient funds
+        assert_eq!(
+            aggregates(None),
+            Aggregates {
+                deposit_rows_total: 15.0,
+                withdrawal_rows_total: 8.0,
+                chargeback_rows: 1,
+            }
+        );
+        assert_eq!(
+            aggregates(Some(2)),
+            Aggregates {
+                deposit_rows_total: 3.0,
+                ..Default::default()
+            }
+        );
+
+        for flag in [
+            &["--strict-held"][..],
+            &["--ignore-zero-disputes"],
+            &["--skip-zero-deposits"],
+            &["--partial-resolve"],
+            &["--resolve-mismatch", "clamp"],
+            &["--format", "parquet"],
+            &["--locked-format", "1-0"],
+            &["--dead-letter", "dead.csv"],
+            &["--extended"],
+            &["--empty-exit"],
+            &["--shards", "2"],
+            &["--explain", "1"],
+            &["--sum-duplicates"],
+            &["--lock-below", "5"],
+            &["--hash"],
+            &["--buffer-settlements"],
+            &["--max-transactions", "3"],
+            &["--since-tx", "3"],
+            &["--on-anomaly", "warn"],
+        ] {
+            let args: Vec<String> = ["--aggregate", "input.csv"]
+                .iter()
+                .chain(flag)
+                .map(|arg| arg.to_string())
+                .collect();
+            assert!(Args::parse(&args).is_err());
+        }
+        let args = [
+            "--aggregate",
+            "--as-of",
+            "2",
+            "--flexible",
+            "--validate",
+            "input.csv",
+        ];
+        assert!(Args::parse(&args.map(String::from)).is_ok());
+    }
+
+    #[test]
+    fn test_double_dispute() {
+        let mut payment_engine = PaymentEngine::default();
+
+        for kind in [
+            TransactionType::Deposit,
+            TransactionType::Dispute,
+            TransactionType::Dispute,
+        ] {
+            payment_engine.process_transaction(Transaction {
+                kind,
+                client: 1,
+                tx: 1,
+                amount: 5.0,
+                state: TransactionState::None,
+                held: 0.0,
+            });
+        }
+
+        let client = payment_engine.clients.get(&1).expect("Client not found");
+        assert_eq!(client.available, 0.0);
+        assert_eq!(client.held, 5.0);
+        assert_eq!(client.total, 5.0);
+        assert_eq!(client.lifetime_disputes, 1);
+        assert_eq!(payment_engine.stats.double_disputes, 1);
+        assert_eq!(
+            payment_engine
+                .executed_transactions
+                .get(&1)
+                .expect("must be available")
+                .state,
+            TransactionState::Dispute
+        );
+    }
+
+    #[test]
+    fn test_state_hash() {
+        let input = generated_input(3, 10, 1_000);
+        let run = |format| {
+            let mut payment_engine = PaymentEngine::default();
+            payment_engine.output.locked_format = format;
+            payment_engine
+                .run_reader(reader_builder(false).from_reader(input.as_bytes()))
+                .unwrap();
+            payment_engine
+        };
+
+        let mut payment_engine = run(LockedFormat::TrueFalse);
+        let hash = payment_engine.state_hash().unwrap();
+        assert_eq!(hash.len(), 64);
+        assert_eq!(run(LockedFormat::YesNo).state_hash().unwrap(), hash);
+
+        payment_engine.clients.get_mut(&1).unwrap().available += 0.01;
+        assert_ne!(payment_engine.state_hash().unwrap(), hash);
+    }
+
+    #[test]
+    fn test_parquet_output() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.output.format = OutputFormat::Parquet;
+        payment_engine.start("test_data/input_2.csv").unwrap();
+        // an open dispute, so held is not zero everywhere
+        for (kind, amount) in [
+            (TransactionType::Deposit, 2.5),
+            (TransactionType::Dispute, 0.0),
+        ] {
+            payment_engine.process_transaction(Transaction {
+                kind,
+                client: 2,
+                tx: 7,
+                amount,
+                state: TransactionState::None,
+                held: 0.0,
+            });
+        }
+        assert_eq!(payment_engine.clients[&2].held, 2.5);
+
+        let mut output = Vec::new();
+        payment_engine.write_output(&mut output).unwrap();
+
+        let batches: Vec<RecordBatch> =
+            ParquetRecordBatchReaderBuilder::try_new(Bytes::from(output))
+                .unwrap()
+                .build()
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), payment_engine.clients.len());
+
+        let clients = batch.column(0).as_primitive::<UInt16Type>();
+        let available = batch.column(1).as_primitive::<Decimal128Type>();
+        let held = batch.column(2).as_primitive::<Decimal128Type>();
+        let total = batch.column(3).as_primitive::<Decimal128Type>();
+        let locked = batch.column(4).as_boolean();
+        for (row, client) in payment_engine.clients.values().enumerate() {
+            assert_eq!(clients.value(row), client.client);
+            assert_eq!(
+                available.value(row),
+                (client.available * 10_000.0).round() as i128
+            );
+            assert_eq!(held.value(row), (client.held * 10_000.0).round() as i128);
+            assert_eq!(total.value(row), (client.total * 10_000.0).round() as i128);
+            assert_eq!(locked.value(row), client.locked);
+        }
+
+        // beyond decimal(20, 4), or overflowed
+        for amount in [1e16, f64::INFINITY, f64::NAN] {
+            payment_engine.clients.get_mut(&1).unwrap().total = amount;
+            assert!(payment_engine.write_output(Vec::new()).is_err());
+        }
+
+        for [flag, value] in [["--locked-format", "1-0"], ["--explain", "1"]] {
+            let args = ["--format", "parquet", flag, value, "input.csv"];
+            assert!(Args::parse(&args.map(String::from)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_buffer_settlements() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.config.buffer_settlements = true;
+
+        payment_engine.process_transaction(transaction(TransactionType::Deposit, 1, 5.0));
+        payment_engine.process_transaction(transaction(TransactionType::Deposit, 2, 3.0));
+        payment_engine.process_transaction(transaction(TransactionType::Resolve, 1, 0.0));
+        payment_engine.process_transaction(transaction(TransactionType::Chargeback, 2, 0.0));
+        assert_eq!(payment_engine.clients[&1].held, 0.0);
+        assert_eq!(payment_engine.pending_settlements.len(), 2);
+
+        payment_engine.process_transaction(transaction(TransactionType::Dispute, 1, 0.0));
+        let client = &payment_engine.clients[&1];
+        assert_eq!(client.available, 8.0);
+        assert_eq!(client.held, 0.0);
+        assert!(!client.locked);
+        assert_eq!(
+            payment_engine.executed_transactions[&1].state,
+            TransactionState::Resolve
+        );
+
+        payment_engine.process_transaction(transaction(TransactionType::Dispute, 2, 0.0));
+        let client = &payment_engine.clients[&1];
+        assert_eq!(client.total, 5.0);
+        assert!(client.locked);
+        assert!(payment_engine.pending_settlements.is_empty());
+    }
+
+    #[test]
+    fn test_max_transactions() {
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.config.max_transactions = Some(3);
+
+        payment_engine.process_transaction(transaction(TransactionType::Deposit, 1, 1.0));
+        payment_engine.process_transaction(transaction(TransactionType::Dispute, 1, 0.0));
+        for tx in 2..=5 {
+            payment_engine.process_transaction(transaction(TransactionType::Deposit, tx, 1.0));
+            assert!(payment_engine.executed_transactions.len() <= 3);
+        }
+        // the disputed deposit is the oldest but pinned, so 2 and 3 go instead
+        let mut kept: Vec<_> = payment_engine
+            .executed_transactions
+            .keys()
+            .copied()
+            .collect();
+        kept.sort();
+        assert_eq!(kept, vec![1, 4, 5]);
+        assert_eq!(payment_engine.stats.evictions, 2);
+
+        // disputing and resolving 4 makes 5 the least recently used
+        payment_engine.process_transaction(transaction(TransactionType::Dispute, 4, 0.0));
+        payment_engine.process_transaction(transaction(TransactionType::Resolve, 4, 0.0));
+        payment_engine.process_transaction(transaction(TransactionType::Deposit, 6, 1.0));
+        let mut kept: Vec<_> = payment_engine
+            .executed_transactions
+            .keys()
+            .copied()
+            .collect();
+        kept.sort();
+        assert_eq!(kept, vec![1, 4, 6]);
+        assert_eq!(
+            payment_engine.executed_transactions[&1].state,
+            TransactionState::Dispute
+        );
+        assert_eq!(payment_engine.stats.evictions, 3);
+
+        // open disputes stay out of the eviction order, and a held back resolve goes with
+        // the transaction it waits for
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.config.max_transactions = Some(1_001);
+        payment_engine.config.buffer_settlements = true;
+        for tx in 1..=1_000 {
+            payment_engine.process_transaction(transaction(TransactionType::Deposit, tx, 1.0));
+            payment_engine.process_transaction(transaction(TransactionType::Dispute, tx, 0.0));
+        }
+        payment_engine.process_transaction(transaction(TransactionType::Deposit, 1_001, 1.0));
+        payment_engine.process_transaction(transaction(TransactionType::Resolve, 1_001, 0.0));
+        assert_eq!(payment_engine.pending_settlements.len(), 1);
+        for tx in 1_002..=1_100 {
+            payment_engine.process_transaction(transaction(TransactionType::Deposit, tx, 1.0));
+        }
+        assert_eq!(payment_engine.executed_transactions.len(), 1_001);
+        assert_eq!(payment_engine.recency.order.len(), 1);
+        assert_eq!(payment_engine.stats.evictions, 99);
+        assert!(payment_engine.pending_settlements.is_empty());
+
+        let args = ["--shards", "2", "--max-transactions", "3", "input.csv"];
+        assert!(Args::parse(&args.map(String::from)).is_err());
+    }
+
+    #[test]
+    fn test_since_tx() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,5.0
+deposit,3,3,5.0
+deposit,1,4,1.0
+dispute,3,3,0
+withdrawal,2,5,100.0
+";
+        let mut payment_engine = PaymentEngine::default();
+        payment_engine.config.since_tx = Some(3);
+        payment_engine
+            .run_reader(reader_builder(false).from_reader(input.as_bytes()))
+            .unwrap();
+
+        // 2 only had a rejected withdrawal past the mark, and the dispute on 3 counts
+        // as past the mark even though it references an earlier id
+        let mut output = Vec::new();
+        payment_engine.write_output(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,6.0,0.0,6.0,false\n3,0.0,5.0,5.0,false\n"
+        );
+        assert_eq!(payment_engine.clients.len(), 3);
+    }
+
+    #[test]
+    fn test_on_anomaly() {
+        let run = |policy, transactions: &[(TransactionType, u32, f64)]| {
+            let mut payment_engine = PaymentEngine::default();
+            payment_engine.config.on_anomaly = policy;
+            for &(kind, tx, amount) in transactions {
+                payment_engine.process_transaction(transaction(kind, tx, amount));
+            }
+            payment_engine
+        };
+        // disputing a deposit that was already withdrawn
+        let negative_available = [
+            (TransactionType::Deposit, 1, 5.0),
+            (TransactionType::Withdrawal, 2, 5.0),
+            (TransactionType::Dispute, 1, 0.0),
+        ];
+        let anomalies = [
+            &negative_available[..],
+            &[
+                (TransactionType::Dispute, 1, 0.0),
+                (TransactionType::Deposit, 3, f64::MAX),
+                (TransactionType::Deposit, 4, f64::MAX),
+            ],
+        ]
+        .concat();
+
+        for policy in [AnomalyPolicy::Skip, AnomalyPolicy::Warn] {
+            let payment_engine = run(policy, &anomalies);
+            assert_eq!(payment_engine.stats.negative_available, 1);
+            assert_eq!(payment_engine.stats.double_disputes, 1);
+            assert_eq!(payment_engine.stats.overflows, 1);
+            assert_eq!(payment_engine.clients[&1].held, 5.0);
+        }
+
+        let panic = std::panic::catch_unwind(|| run(AnomalyPolicy::Panic, &negative_available))
+            .err()
+            .expect("must panic");
+        assert_eq!(
+            panic.downcast_ref::<String>().unwrap(),
+            "anomaly: dispute of transaction 1 drives available funds negative"
+        );
+    }
 }